@@ -6,11 +6,21 @@ use std::path::Path;
 fn main() {
     let dst = env::var("OUT_DIR").unwrap();
 
+    let mut copt = "-fPIC".to_string();
+    if env::var_os("CARGO_FEATURE_SSL").is_some() {
+        copt.push_str(" -DNO_SSL_DL");
+        if let Some(include) = env::var_os("DEP_OPENSSL_INCLUDE") {
+            for dir in env::split_paths(&include) {
+                copt.push_str(&format!(" -I{}", dir.display()));
+            }
+        }
+    }
+
     assert!(Command::new("make")
                     .current_dir("civetweb")
                     .arg("lib")
                     .arg(&format!("BUILD_DIR={}", dst))
-                    .env("COPT", "-fPIC")
+                    .env("COPT", &copt)
                     .status().unwrap().success());
 
     {
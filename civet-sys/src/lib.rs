@@ -1,3 +1,7 @@
+// Pulls in the link flags for the OpenSSL civetweb is compiled against
+#[cfg(feature = "ssl")]
+extern crate openssl_sys;
+
 #[test]
 fn it_works() {
 }
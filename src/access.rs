@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use conduit::{header, Body, Response, StatusCode};
+
+use cidr::Cidr;
+
+/// Rate limiter entries kept before idle clients are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Restricts which clients may use a mount, and how heavily.
+///
+/// Register one per URI prefix with `Config::access_control`. Clients
+/// refused by the CIDR rules get a 403, and clients over a connection or
+/// rate limit get a 429.
+///
+/// ```
+/// # extern crate civet;
+/// use std::time::Duration;
+/// use civet::{AccessControl, Config};
+///
+/// let mut admin = AccessControl::new();
+/// admin
+///     .allow("10.0.0.0/8")
+///     .allow("fd00::/8")
+///     .max_connections_per_ip(4)
+///     .rate_limit(100, Duration::from_secs(60));
+///
+/// let mut config = Config::new();
+/// config.access_control("/admin/", admin);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+    rules: Vec<(bool, String)>,
+    max_connections: Option<usize>,
+    rate: Option<(u32, Duration)>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl {
+            rules: Vec::new(),
+            max_connections: None,
+            rate: None,
+        }
+    }
+
+    /// Allows clients in `cidr`, such as `10.0.0.0/8` or `fd00::/8`.
+    ///
+    /// Once any network is allowed, clients outside every allowed network
+    /// are refused. Later rules take precedence.
+    pub fn allow(&mut self, cidr: &str) -> &mut AccessControl {
+        self.rules.push((true, cidr.to_string()));
+        self
+    }
+
+    /// Refuses clients in `cidr`. Later rules take precedence.
+    pub fn deny(&mut self, cidr: &str) -> &mut AccessControl {
+        self.rules.push((false, cidr.to_string()));
+        self
+    }
+
+    /// Limits how many connections one IP may have open under the mount at
+    /// once.
+    ///
+    /// A connection counts from its first request under the mount until it
+    /// closes, so further requests on a keep-alive connection are not
+    /// limited by it.
+    pub fn max_connections_per_ip(&mut self, max: usize) -> &mut AccessControl {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Limits one IP to `requests` requests under the mount per `period`.
+    ///
+    /// Requests may arrive in bursts of up to `requests` as long as the
+    /// average rate stays under the limit.
+    pub fn rate_limit(&mut self, requests: u32, period: Duration) -> &mut AccessControl {
+        self.rate = Some((requests, period));
+        self
+    }
+}
+
+/// An `AccessControl` with its rules parsed, along with its counters.
+pub(crate) struct Mount {
+    prefix: String,
+    rules: Vec<(bool, Cidr)>,
+    default_allow: bool,
+    max_connections: Option<usize>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    rate: Option<(u32, Duration)>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Mount {
+    pub(crate) fn new(prefix: String, access: AccessControl) -> io::Result<Mount> {
+        let rules = access
+            .rules
+            .iter()
+            .map(|(allow, cidr)| {
+                let cidr = cidr
+                    .parse::<Cidr>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+                Ok((*allow, cidr))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Mount {
+            prefix,
+            default_allow: !rules.iter().any(|&(allow, _)| allow),
+            rules,
+            max_connections: access.max_connections,
+            connections: Arc::default(),
+            rate: access.rate,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(_, cidr)| cidr.contains(ip))
+            .map_or(self.default_allow, |&(allow, _)| allow)
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one
+    /// is available.
+    fn take_token(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (requests, period) = match self.rate {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        if requests == 0 {
+            return Err(period);
+        }
+        let capacity = requests as f64;
+        let per_sec = capacity / period.as_secs_f64();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A bucket idle for a whole period is full again
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < period);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    /// Counts a connection from `ip` against the mount's connection limit,
+    /// unless one of the `held` permits already does.
+    fn enter(&self, ip: IpAddr, held: &mut Vec<Permit>) -> bool {
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return true,
+        };
+        let holding = held
+            .iter()
+            .any(|permit| permit.ip == ip && Arc::ptr_eq(&permit.connections, &self.connections));
+        if holding {
+            return true;
+        }
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let count = connections.entry(ip).or_insert(0);
+        if *count >= max {
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+            return false;
+        }
+        *count += 1;
+        held.push(Permit {
+            connections: self.connections.clone(),
+            ip,
+        });
+        true
+    }
+}
+
+/// Holds a connection's place under a mount's connection limit until
+/// dropped.
+pub(crate) struct Permit {
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Applies the mount with the longest prefix matching `path`.
+///
+/// `held` are the permits of the connection the request arrived on, to be
+/// kept until it closes. A permit is added to them the first time the
+/// connection uses a mount with a connection limit.
+pub(crate) fn check(
+    mounts: &[Mount],
+    path: &str,
+    ip: IpAddr,
+    held: &mut Vec<Permit>,
+) -> Result<(), Refusal> {
+    let mount = match mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.prefix))
+        .max_by_key(|mount| mount.prefix.len())
+    {
+        Some(mount) => mount,
+        None => return Ok(()),
+    };
+
+    if !mount.allows(ip) {
+        return Err(Refusal::Forbidden);
+    }
+    if !mount.enter(ip, held) {
+        return Err(Refusal::TooManyConnections);
+    }
+    if let Err(wait) = mount.take_token(ip, Instant::now()) {
+        return Err(Refusal::RateLimited(wait));
+    }
+    Ok(())
+}
+
+/// Why `check` refused a request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Forbidden,
+    TooManyConnections,
+    RateLimited(Duration),
+}
+
+impl Refusal {
+    pub(crate) fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_LENGTH, 0.into());
+        if let Refusal::RateLimited(wait) = *self {
+            // Round up so that a client honouring the header is let through
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            headers.insert(header::RETRY_AFTER, secs.into());
+        }
+        *response.status_mut() = match *self {
+            Refusal::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        };
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, AccessControl, Mount};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn status(mounts: &[Mount], path: &str, client: &str) -> u16 {
+        match check(mounts, path, ip(client), &mut Vec::new()) {
+            Ok(_) => 200,
+            Err(refusal) => refusal.response().status().as_u16(),
+        }
+    }
+
+    #[test]
+    fn cidr_rules_per_mount() {
+        let mut admin = AccessControl::new();
+        admin.allow("10.0.0.0/8").deny("10.6.0.0/16");
+        let mut public = AccessControl::new();
+        public.deny("192.0.2.0/24");
+        let mounts = vec![
+            Mount::new("/".to_string(), public).unwrap(),
+            Mount::new("/admin/".to_string(), admin).unwrap(),
+        ];
+
+        assert_eq!(status(&mounts, "/admin/users", "10.1.1.1"), 200);
+        assert_eq!(status(&mounts, "/admin/users", "10.6.1.1"), 403);
+        assert_eq!(status(&mounts, "/admin/users", "203.0.113.9"), 403);
+        assert_eq!(status(&mounts, "/index.html", "203.0.113.9"), 200);
+        assert_eq!(status(&mounts, "/index.html", "192.0.2.1"), 403);
+    }
+
+    #[test]
+    fn invalid_cidr_is_an_error() {
+        let mut access = AccessControl::new();
+        access.allow("10.0.0.0/40");
+        assert!(Mount::new("/".to_string(), access).is_err());
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let mut access = AccessControl::new();
+        access.max_connections_per_ip(2);
+        let mounts = vec![Mount::new("/".to_string(), access).unwrap()];
+
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        check(&mounts, "/", ip("10.0.0.1"), &mut first).unwrap();
+        check(&mounts, "/", ip("10.0.0.1"), &mut second).unwrap();
+        assert_eq!(status(&mounts, "/", "10.0.0.1"), 429);
+        assert_eq!(status(&mounts, "/", "10.0.0.2"), 200);
+        // Later requests on an open connection are not counted again
+        check(&mounts, "/", ip("10.0.0.1"), &mut first).unwrap();
+        assert_eq!(first.len(), 1);
+        drop(first);
+        assert_eq!(status(&mounts, "/", "10.0.0.1"), 200);
+        drop(second);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut access = AccessControl::new();
+        access.rate_limit(2, Duration::from_secs(10));
+        let mount = Mount::new("/".to_string(), access).unwrap();
+
+        let start = Instant::now();
+        let client = ip("10.0.0.1");
+        assert!(mount.take_token(client, start).is_ok());
+        assert!(mount.take_token(client, start).is_ok());
+        assert_eq!(mount.take_token(client, start), Err(Duration::from_secs(5)));
+        assert!(mount.take_token(ip("10.0.0.2"), start).is_ok());
+        assert!(mount
+            .take_token(client, start + Duration::from_secs(5))
+            .is_ok());
+    }
+}
@@ -0,0 +1,159 @@
+use conduit::{header, Body, HeaderMap, RequestExt, Response, StatusCode};
+
+/// Checks the credentials sent with HTTP Basic authentication.
+///
+/// Register one with `Config::basic_auth` to protect a URI prefix with a
+/// user store of your own. Closures with a matching signature implement this
+/// trait.
+///
+/// ```
+/// # extern crate civet;
+/// use civet::Config;
+///
+/// let mut config = Config::new();
+/// config.basic_auth("/admin/", |user: &str, password: &str| {
+///     user == "admin" && password == "hunter2"
+/// });
+/// ```
+pub trait Authenticator: Sync + Send + 'static {
+    /// Returns whether `password` is correct for `user`.
+    fn authenticate(&self, user: &str, password: &str) -> bool;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&str, &str) -> bool + Sync + Send + 'static,
+{
+    fn authenticate(&self, user: &str, password: &str) -> bool {
+        (*self)(user, password)
+    }
+}
+
+/// The authenticated user of a request.
+///
+/// Inserted into the request's extensions when civetweb's digest
+/// authentication or an `Authenticator` accepted the request's credentials.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteUser(pub String);
+
+/// An `Authenticator` protecting every path that starts with `prefix`.
+pub(crate) struct BasicAuth {
+    pub(crate) prefix: String,
+    pub(crate) authenticator: Box<dyn Authenticator>,
+}
+
+/// Applies the rule with the longest prefix matching the request's path.
+///
+/// Returns the 401 challenge to send if the request is not allowed through.
+pub(crate) fn check(
+    rules: &[BasicAuth],
+    realm: &str,
+    request: &mut dyn RequestExt,
+) -> Option<Response<Body>> {
+    let rule = rules
+        .iter()
+        .filter(|rule| request.path().starts_with(&rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())?;
+
+    if let Some((user, password)) = credentials(request.headers()) {
+        if rule.authenticator.authenticate(&user, &password) {
+            request.mut_extensions().insert(RemoteUser(user));
+            return None;
+        }
+    }
+    Some(unauthorized(realm))
+}
+
+fn unauthorized(realm: &str) -> Response<Body> {
+    let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, 0.into());
+    if let Ok(challenge) = header::HeaderValue::from_str(&challenge) {
+        headers.insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+/// Reads the user and password from a `Basic` `Authorization` header.
+fn credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?.trim();
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(decode_base64(parts.next()?.trim())?).ok()?;
+    let colon = decoded.find(':')?;
+    Some((
+        decoded[..colon].to_string(),
+        decoded[colon + 1..].to_string(),
+    ))
+}
+
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.trim_end_matches('=').as_bytes();
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        let mut bits = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            bits |= value(c)? << (18 - 6 * i);
+        }
+        let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+        out.extend_from_slice(&bytes[..chunk.len() - 1]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{credentials, decode_base64};
+    use conduit::{header, HeaderMap};
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+        assert_eq!(decode_base64("Zm9vYg").unwrap(), b"foob");
+        assert!(decode_base64("Zm9vY").is_none());
+        assert!(decode_base64("Zm9v!").is_none());
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(credentials(&headers), None);
+
+        // "aladdin:open:sesame"
+        headers.insert(
+            header::AUTHORIZATION,
+            "basic YWxhZGRpbjpvcGVuOnNlc2FtZQ==".parse().unwrap(),
+        );
+        assert_eq!(
+            credentials(&headers),
+            Some(("aladdin".to_string(), "open:sesame".to_string()))
+        );
+
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        assert_eq!(credentials(&headers), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::io::{self, Read, Write};
+
+use conduit::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Longest chunk-size or trailer line accepted before the body is rejected.
+const MAX_LINE: usize = 8 * 1024;
+
+/// The trailer fields sent after a `Transfer-Encoding: chunked` request body.
+///
+/// Inserted into the request's extensions once the body has been read to the
+/// end. The map is empty if the client sent no trailers.
+pub struct Trailers(pub HeaderMap);
+
+enum State {
+    Size,
+    Data(u64),
+    DataEnd,
+    Done,
+}
+
+/// Decodes a chunked request body.
+///
+/// The reader never consumes bytes past the end of the body, so it is safe to
+/// use on a keep-alive connection.
+pub struct ChunkedReader<R> {
+    inner: R,
+    state: State,
+    trailers: Option<HeaderMap>,
+    /// Largest number of decoded bytes accepted, if any.
+    limit: Option<u64>,
+    /// Decoded bytes announced by the chunk sizes so far.
+    total: u64,
+}
+
+impl<R: Read> ChunkedReader<R> {
+    pub fn new(inner: R, limit: Option<u64>) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            state: State::Size,
+            trailers: None,
+            limit,
+            total: 0,
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Takes the trailers once the final chunk has been read.
+    pub fn take_trailers(&mut self) -> Option<HeaderMap> {
+        self.trailers.take()
+    }
+
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            if self.inner.read(&mut byte)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of chunked body",
+                ));
+            }
+            if byte[0] == b'\n' {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            if line.len() == MAX_LINE {
+                return Err(invalid("chunked body line too long"));
+            }
+            line.push(byte[0]);
+        }
+    }
+
+    fn read_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line()?;
+        // Chunk extensions are allowed after a `;` and are ignored
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size)
+            .ok()
+            .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+            .ok_or_else(|| invalid("invalid chunk size"))?;
+        Ok(size)
+    }
+
+    fn read_trailers(&mut self) -> io::Result<HeaderMap> {
+        let mut trailers = HeaderMap::new();
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(trailers);
+            }
+            let colon = line
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| invalid("invalid trailer"))?;
+            let name = HeaderName::from_bytes(&line[..colon])
+                .map_err(|_| invalid("invalid trailer name"))?;
+            let value = HeaderValue::from_bytes(trim(&line[colon + 1..]))
+                .map_err(|_| invalid("invalid trailer value"))?;
+            trailers.append(name, value);
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.state {
+                State::Size => {
+                    let size = self.read_size()?;
+                    self.total = self.total.saturating_add(size);
+                    if self.limit.is_some_and(|limit| self.total > limit) {
+                        return Err(invalid("request body too large"));
+                    }
+                    if size == 0 {
+                        self.trailers = Some(self.read_trailers()?);
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Data(size);
+                    }
+                }
+                State::Data(remaining) => {
+                    let max = std::cmp::min(remaining, buf.len() as u64) as usize;
+                    let n = self.inner.read(&mut buf[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "unexpected end of chunked body",
+                        ));
+                    }
+                    self.state = if remaining == n as u64 {
+                        State::DataEnd
+                    } else {
+                        State::Data(remaining - n as u64)
+                    };
+                    return Ok(n);
+                }
+                State::DataEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(invalid("missing CRLF after chunk data"));
+                    }
+                    self.state = State::Size;
+                }
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Encodes a response body with `Transfer-Encoding: chunked`.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    /// Writes the final, empty chunk.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns whether `chunked` is the final transfer coding of a request.
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(conduit::header::TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let Some((first, rest)) = bytes.split_first() {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        bytes = rest;
+    }
+    while let Some((last, rest)) = bytes.split_last() {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        bytes = rest;
+    }
+    bytes
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChunkedReader, ChunkedWriter};
+    use std::io::{Cursor, Read, Write};
+
+    fn decode(input: &str) -> (std::io::Result<String>, ChunkedReader<Cursor<Vec<u8>>>) {
+        decode_limited(input, None)
+    }
+
+    fn decode_limited(
+        input: &str,
+        limit: Option<u64>,
+    ) -> (std::io::Result<String>, ChunkedReader<Cursor<Vec<u8>>>) {
+        let mut reader = ChunkedReader::new(Cursor::new(input.as_bytes().to_vec()), limit);
+        let mut body = String::new();
+        let res = reader.read_to_string(&mut body).map(|_| body);
+        (res, reader)
+    }
+
+    #[test]
+    fn decodes_chunks() {
+        let (body, mut reader) = decode("4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n");
+        assert_eq!(body.unwrap(), "Wikipedia");
+        assert!(reader.take_trailers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn collects_trailers() {
+        let (body, mut reader) = decode("3\r\nabc\r\n0\r\nX-Checksum: 123\r\n\r\n");
+        assert_eq!(body.unwrap(), "abc");
+        let trailers = reader.take_trailers().unwrap();
+        assert_eq!(trailers.get("x-checksum").unwrap(), "123");
+    }
+
+    #[test]
+    fn does_not_read_past_body() {
+        let (body, reader) = decode("1\r\na\r\n0\r\n\r\nGET / HTTP/1.1\r\n");
+        assert_eq!(body.unwrap(), "a");
+        assert_eq!(reader.inner.position(), 11);
+    }
+
+    #[test]
+    fn limits_decoded_size() {
+        let body = "4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(decode_limited(body, Some(9)).0.unwrap(), "Wikipedia");
+        let err = decode_limited(body, Some(8)).0.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn round_trips_through_writer() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"Wiki").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"pedia").unwrap();
+        let encoded = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(encoded, "4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        assert_eq!(decode(&encoded).0.unwrap(), "Wikipedia");
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        assert!(decode("zz\r\nabc\r\n0\r\n\r\n").0.is_err());
+        assert!(decode("3\r\nabcd\r\n0\r\n\r\n").0.is_err());
+        assert!(decode("5\r\nabc").0.is_err());
+    }
+}
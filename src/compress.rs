@@ -0,0 +1,373 @@
+use std::io::{self, Read, Write};
+
+use conduit::{header, Body, HeaderMap, Method, RequestExt, Response, StatusCode, Version};
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+/// Settings for compressing responses.
+///
+/// Register with `Config::compression`. A response is compressed when the
+/// client accepts one of the supported encodings, its `Content-Type` is on
+/// the allow-list, it is at least `min_size` bytes, and the handler did not
+/// set a `Content-Encoding` itself. Brotli needs the `brotli` feature; gzip
+/// and deflate are always available.
+///
+/// ```
+/// # extern crate civet;
+/// use civet::{Compression, Config};
+///
+/// let mut compression = Compression::new();
+/// compression.min_size(512).level(9).mime_type("application/x-ndjson");
+///
+/// let mut config = Config::new();
+/// config.compression(compression);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+    brotli_quality: u32,
+    mime_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            level: 6,
+            brotli_quality: 5,
+            mime_types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/wasm",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        }
+    }
+
+    /// Bodies smaller than this are sent as they are. Defaults to 1 KiB.
+    pub fn min_size(&mut self, bytes: u64) -> &mut Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The gzip and deflate level, from 0 to 9. Defaults to 6.
+    pub fn level(&mut self, level: u32) -> &mut Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    /// The brotli quality, from 0 to 11. Defaults to 5.
+    pub fn brotli_quality(&mut self, quality: u32) -> &mut Compression {
+        self.brotli_quality = quality.min(11);
+        self
+    }
+
+    /// Adds a MIME type to compress, such as `application/json` or
+    /// `text/*`.
+    pub fn mime_type(&mut self, mime_type: &str) -> &mut Compression {
+        self.mime_types.push(mime_type.to_ascii_lowercase());
+        self
+    }
+
+    /// Replaces the MIME types to compress.
+    ///
+    /// Defaults to `text/*` and common uncompressed application formats
+    /// such as JSON, JavaScript and SVG.
+    pub fn mime_types<I, S>(&mut self, mime_types: I) -> &mut Compression
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.mime_types = mime_types
+            .into_iter()
+            .map(|s| s.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    fn compresses(&self, headers: &HeaderMap) -> bool {
+        let content_type = match headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.mime_types.iter().any(|pattern| {
+            if pattern.ends_with("/*") {
+                essence.starts_with(&pattern[..pattern.len() - 1])
+            } else {
+                essence == *pattern
+            }
+        })
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Supported encodings, most preferred first.
+    const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the encoding the client rates highest in `Accept-Encoding`,
+/// breaking ties by our own preference.
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut ratings = Vec::new();
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if param.len() > 2 && param[..2].eq_ignore_ascii_case("q=") {
+                        param[2..].trim().parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            ratings.push((coding, q));
+        }
+    }
+    let rating = |name: &str| {
+        let explicit = ratings.iter().find(|(coding, _)| coding == name);
+        let wildcard = ratings.iter().find(|(coding, _)| coding == "*");
+        explicit.or(wildcard).map_or(0.0, |&(_, q)| q)
+    };
+
+    let mut best = None;
+    for &encoding in Encoding::ALL {
+        let q = rating(encoding.as_str());
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses `response` for `request` if the settings allow it.
+///
+/// In-memory bodies are compressed here. For `Body::File` the headers are
+/// updated for a chunked, encoded body and the encoding is returned, so that
+/// the file can be compressed as it is written.
+pub(crate) fn apply(
+    settings: &Compression,
+    request: &dyn RequestExt,
+    response: &mut Response<Body>,
+) -> Option<Encoding> {
+    if !settings.compresses(response.headers()) {
+        return None;
+    }
+    let headers = response.headers_mut();
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(
+            header::VARY,
+            header::HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+
+    let status = response.status();
+    if response.headers().contains_key(header::CONTENT_ENCODING)
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || *request.method() == Method::HEAD
+    {
+        return None;
+    }
+    let encoding = negotiate(request.headers())?;
+
+    let len = match response.body() {
+        Body::Static(slice) => slice.len() as u64,
+        Body::Owned(vec) => vec.len() as u64,
+        Body::File(file) => file.metadata().map(|m| m.len()).unwrap_or(u64::MAX),
+    };
+    if len < settings.min_size {
+        return None;
+    }
+
+    let streamed = match response.body() {
+        Body::File(_) => {
+            // HTTP/1.0 has no chunked encoding to delimit the body with
+            if request.http_version() < Version::HTTP_11 {
+                return None;
+            }
+            true
+        }
+        Body::Static(_) | Body::Owned(_) => false,
+    };
+    if streamed {
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            header::HeaderValue::from_static("chunked"),
+        );
+    } else {
+        let compressed = {
+            let mut input = match response.body() {
+                Body::Static(slice) => *slice,
+                Body::Owned(vec) => &vec[..],
+                Body::File(_) => unreachable!(),
+            };
+            match encode(encoding, settings, &mut input, Vec::new()) {
+                Ok(compressed) if (compressed.len() as u64) < len => compressed,
+                _ => return None,
+            }
+        };
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, compressed.len().into());
+        *response.body_mut() = Body::Owned(compressed);
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static(encoding.as_str()),
+    );
+    // The encoded representation is no longer byte-for-byte the same
+    if let Some(etag) = headers.get(header::ETAG).cloned() {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = header::HeaderValue::from_bytes(&weak) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+
+    if streamed {
+        Some(encoding)
+    } else {
+        None
+    }
+}
+
+/// Compresses everything read from `input` into `output`.
+pub(crate) fn encode<W: Write>(
+    encoding: Encoding,
+    settings: &Compression,
+    input: &mut dyn Read,
+    output: W,
+) -> io::Result<W> {
+    let level = flate2::Compression::new(settings.level);
+    match encoding {
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli => {
+            let mut encoder =
+                brotli::CompressorWriter::new(output, 4096, settings.brotli_quality, 22);
+            io::copy(input, &mut encoder)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(output, level);
+            io::copy(input, &mut encoder)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(output, level);
+            io::copy(input, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, negotiate, Compression, Encoding};
+    use conduit::{header, HeaderMap};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn accept(value: &str) -> Option<Encoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+        negotiate(&headers)
+    }
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+        assert_eq!(accept("identity"), None);
+        assert_eq!(accept("deflate"), Some(Encoding::Deflate));
+        assert_eq!(accept("deflate, gzip"), Some(Encoding::Gzip));
+        assert_eq!(accept("gzip;q=0.5, deflate;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(accept("*;q=0.1, gzip;q=0, br;q=0"), Some(Encoding::Deflate));
+        #[cfg(feature = "brotli")]
+        assert_eq!(accept("gzip, br"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = "hello ".repeat(1000);
+        let settings = Compression::new();
+        let compressed =
+            encode(Encoding::Gzip, &settings, &mut body.as_bytes(), Vec::new()).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+}
@@ -1,60 +1,1178 @@
 use libc::c_char;
+use std::env;
+use std::error::Error;
 use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::time::Duration;
 
+use access::AccessControl;
+use auth::{Authenticator, BasicAuth};
+use compress::Compression;
+use error::ErrorHandler;
+use hooks::ServerHooks;
+use metrics::Metrics;
+use panics::PanicReporter;
+use proxy::ProxyProtocol;
+use redirect::HttpsRedirect;
+use security::SecurityHeaders;
+#[cfg(feature = "tls")]
+use tls::SniCertificates;
+
+/// Server configuration.
+///
+/// A `Config` can be built in code, or loaded with `Config::from_file` and
+/// `Config::from_env`. When several sources are combined, later ones take
+/// precedence:
+///
+/// 1. civetweb's built-in defaults
+/// 2. the configuration file
+/// 3. environment variables, applied with `Config::apply_env`
+/// 4. builder methods called after loading
+///
+/// ```no_run
+/// # fn main() -> Result<(), civet::ConfigError> {
+/// let mut config = civet::Config::from_file("civet.conf")?;
+/// config.apply_env("CIVET_")?;
+/// config.threads(8);
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Default)]
 pub struct Config {
-    port: Option<u16>,
-    threads: Option<u32>,
+    pub(crate) port: Option<u16>,
+    pub(crate) https_ports: Vec<u16>,
+    pub(crate) redirect_listeners: Vec<(u16, HttpsRedirect)>,
+    pub(crate) ssl_certificate: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub(crate) sni_certificates: Option<SniCertificates>,
+    pub(crate) threads: Option<u32>,
     enable_keep_alive: Option<bool>,
+    pub(crate) request_timeout: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    linger_timeout: Option<Duration>,
+    tcp_nodelay: Option<bool>,
+    throttle: Vec<(String, u64)>,
+    access_control_list: Vec<(bool, String)>,
+    pub(crate) authentication_domain: Option<String>,
+    pub(crate) global_auth_file: Option<PathBuf>,
+    pub(crate) protect_uri: Vec<(String, PathBuf)>,
+    pub(crate) max_request_size: Option<u64>,
+    pub(crate) error_pages: Option<PathBuf>,
+    hide_files_patterns: Vec<String>,
+    options: Vec<(String, String)>,
+    pub(crate) error_handler: Option<Box<dyn ErrorHandler>>,
+    pub(crate) panic_reporter: Option<Box<dyn PanicReporter>>,
+    pub(crate) bad_request_body: Option<Vec<u8>>,
+    pub(crate) request_id_header: Option<String>,
+    pub(crate) hooks: Option<Box<dyn ServerHooks>>,
+    pub(crate) basic_auth: Vec<BasicAuth>,
+    pub(crate) access_control: Vec<(String, AccessControl)>,
+    pub(crate) trusted_proxies: Vec<String>,
+    pub(crate) proxy_listeners: Vec<(u16, ProxyProtocol)>,
+    pub(crate) loopback: bool,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) decompress_requests: bool,
+    pub(crate) max_decoded_body_size: Option<u64>,
+    pub(crate) metrics: Metrics,
+    pub(crate) security_headers: Option<SecurityHeaders>,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             port: None,
+            https_ports: Vec::new(),
+            redirect_listeners: Vec::new(),
+            ssl_certificate: None,
+            #[cfg(feature = "tls")]
+            sni_certificates: None,
             threads: None,
             enable_keep_alive: None,
+            request_timeout: None,
+            keep_alive_timeout: None,
+            linger_timeout: None,
+            tcp_nodelay: None,
+            throttle: Vec::new(),
+            access_control_list: Vec::new(),
+            authentication_domain: None,
+            global_auth_file: None,
+            protect_uri: Vec::new(),
+            max_request_size: None,
+            error_pages: None,
+            hide_files_patterns: Vec::new(),
+            options: Vec::new(),
+            error_handler: None,
+            panic_reporter: None,
+            bad_request_body: None,
+            request_id_header: None,
+            hooks: None,
+            basic_auth: Vec::new(),
+            access_control: Vec::new(),
+            trusted_proxies: Vec::new(),
+            proxy_listeners: Vec::new(),
+            loopback: false,
+            compression: None,
+            decompress_requests: false,
+            max_decoded_body_size: None,
+            metrics: Metrics::default(),
+            security_headers: None,
         }
     }
 
     pub fn port(&mut self, port: u16) -> &mut Config {
+        self.remove_option("listening_ports");
         self.port = Some(port);
         self
     }
 
+    /// Listens on `port` for HTTPS connections, using the certificate set
+    /// with `ssl_certificate`.
+    ///
+    /// With the `tls` feature civetweb is linked to the system's OpenSSL.
+    /// Without it, civetweb 1.6 loads OpenSSL 1.0 itself when starting, and
+    /// starting fails with its error if that library isn't installed.
+    pub fn https_port(&mut self, port: u16) -> &mut Config {
+        self.remove_option("listening_ports");
+        self.https_ports.push(port);
+        self
+    }
+
+    /// A PEM file holding the server's private key and certificate chain.
+    pub fn ssl_certificate<P: AsRef<Path>>(&mut self, file: P) -> &mut Config {
+        self.remove_option("ssl_certificate");
+        self.ssl_certificate = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Serves different certificates depending on the hostname clients ask
+    /// for, falling back to `ssl_certificate`.
+    ///
+    /// Requires the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn sni_certificates(&mut self, certificates: SniCertificates) -> &mut Config {
+        self.sni_certificates = Some(certificates);
+        self
+    }
+
+    /// Listens on `port` for plain HTTP requests and redirects each one to
+    /// the same host, path and query on the first `https_port`.
+    ///
+    /// The redirects are answered before civetweb or the handler see the
+    /// request. Starting the server fails with `InvalidInput` if no HTTPS
+    /// port is set.
+    pub fn redirect_to_https(&mut self, port: u16, status: HttpsRedirect) -> &mut Config {
+        self.redirect_listeners.push((port, status));
+        self
+    }
+
     pub fn threads(&mut self, threads: u32) -> &mut Config {
+        self.remove_option("num_threads");
         self.threads = Some(threads);
         self
     }
 
     pub fn keep_alive(&mut self, keep_alive: bool) -> &mut Config {
+        self.remove_option("enable_keep_alive");
         self.enable_keep_alive = Some(keep_alive);
         self
     }
+
+    /// How long to wait on a client while reading a request or writing a
+    /// response before giving up on the connection.
+    ///
+    /// Reads from the request body and writes to the connection fail with
+    /// `io::ErrorKind::TimedOut` once this is exceeded. civetweb also uses
+    /// it as the time an idle keep-alive connection waits for the next
+    /// request.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.remove_option("request_timeout_ms");
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// How long an idle keep-alive connection is held open waiting for the
+    /// next request.
+    ///
+    /// civetweb 1.6 has no such setting and waits for `request_timeout`
+    /// instead, so starting a server with this set fails with
+    /// `io::ErrorKind::Unsupported`.
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to linger on a closing socket so unsent data can still be
+    /// delivered.
+    ///
+    /// civetweb 1.6 always lingers for one second, so starting a server with
+    /// this set fails with `io::ErrorKind::Unsupported`.
+    pub fn linger_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.linger_timeout = Some(timeout);
+        self
+    }
+
+    /// Whether to set `TCP_NODELAY` on accepted connections.
+    ///
+    /// civetweb 1.6 has no such setting and does not expose its sockets, so
+    /// starting a server with this set fails with
+    /// `io::ErrorKind::Unsupported`.
+    pub fn tcp_nodelay(&mut self, nodelay: bool) -> &mut Config {
+        self.tcp_nodelay = Some(nodelay);
+        self
+    }
+
+    /// Limits the transfer rate, in bytes per second, for requests matching
+    /// `target`.
+    ///
+    /// `target` is `*` for all requests, an IP subnet such as `10.0.0.0/8`,
+    /// or a URI prefix such as `/downloads/`. A limit of 0 disables
+    /// throttling for that target. Later rules take precedence.
+    pub fn throttle(&mut self, target: &str, bytes_per_sec: u64) -> &mut Config {
+        self.remove_option("throttle");
+        self.throttle.push((target.to_string(), bytes_per_sec));
+        self
+    }
+
+    /// Allows connections from `subnet`, such as `192.168.0.0/16`.
+    ///
+    /// Once any subnet is allowed, connections from all other addresses are
+    /// refused. Later rules take precedence.
+    pub fn allow_subnet(&mut self, subnet: &str) -> &mut Config {
+        self.remove_option("access_control_list");
+        self.access_control_list.push((true, subnet.to_string()));
+        self
+    }
+
+    /// Refuses connections from `subnet`. Later rules take precedence.
+    pub fn deny_subnet(&mut self, subnet: &str) -> &mut Config {
+        self.remove_option("access_control_list");
+        self.access_control_list.push((false, subnet.to_string()));
+        self
+    }
+
+    /// The realm used for HTTP authentication challenges.
+    pub fn authentication_domain(&mut self, domain: &str) -> &mut Config {
+        self.remove_option("authentication_domain");
+        self.authentication_domain = Some(domain.to_string());
+        self
+    }
+
+    /// An `.htpasswd` file of digest credentials that every request must
+    /// match.
+    ///
+    /// The name of the authenticated user is available to handlers as a
+    /// `RemoteUser` extension. civetweb 1.6 does not check `PUT` and
+    /// `DELETE` requests itself, so the server checks those before calling
+    /// the handler.
+    pub fn global_auth_file<P: AsRef<Path>>(&mut self, file: P) -> &mut Config {
+        self.remove_option("global_auth_file");
+        self.global_auth_file = Some(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Requires digest credentials from the `.htpasswd` `file` for requests
+    /// under `uri_prefix`, checked like `global_auth_file`.
+    pub fn protect_uri<P: AsRef<Path>>(&mut self, uri_prefix: &str, file: P) -> &mut Config {
+        self.remove_option("protect_uri");
+        self.protect_uri
+            .push((uri_prefix.to_string(), file.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Applies `access` to requests under `uri_prefix`.
+    ///
+    /// When several prefixes match a request the longest one applies. The
+    /// CIDR rules are checked when the server starts.
+    pub fn access_control(&mut self, uri_prefix: &str, access: AccessControl) -> &mut Config {
+        self.access_control.push((uri_prefix.to_string(), access));
+        self
+    }
+
+    /// Compresses responses for clients that accept it.
+    pub fn compression(&mut self, compression: Compression) -> &mut Config {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Adds security headers to responses that lack them.
+    pub fn security_headers(&mut self, headers: SecurityHeaders) -> &mut Config {
+        self.security_headers = Some(headers);
+        self
+    }
+
+    /// The metrics of the server started with this configuration, for
+    /// routing a `PrometheusExporter` before the server exists.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Decodes gzip and deflate request bodies, and brotli with the `brotli`
+    /// feature, before the handler reads them.
+    ///
+    /// `max_request_size` still limits the encoded body, and
+    /// `max_decoded_body_size` the decoded one. Bodies in other codings are
+    /// refused with 415 Unsupported Media Type.
+    pub fn decompress_requests(&mut self, decompress: bool) -> &mut Config {
+        self.decompress_requests = decompress;
+        self
+    }
+
+    /// The most bytes the handler may read from a body decoded by
+    /// `decompress_requests`, 16 MiB by default. Reading past the limit fails
+    /// with `InvalidData`.
+    pub fn max_decoded_body_size(&mut self, bytes: u64) -> &mut Config {
+        self.max_decoded_body_size = Some(bytes);
+        self
+    }
+
+    /// Listens on `port` for connections that start with a PROXY protocol
+    /// header, as sent by HAProxy and most TCP load balancers.
+    ///
+    /// Connections without a valid header of the given `version` are
+    /// closed. For the rest, `remote_addr` reports the client named in the
+    /// header, and a v2 header's TLS details are available as a `ProxyTls`
+    /// extension.
+    ///
+    /// The headers are stripped by a relay that hands connections to
+    /// civetweb over a loopback port, serving up to 1024 connections per
+    /// listener at once. A local client that connects to the loopback port
+    /// itself is served as if it had connected to `Config::port`. A server
+    /// with no `port` or `https_port` answers such requests with 403
+    /// Forbidden, so that it requires the header on every connection.
+    pub fn proxy_protocol_listener(&mut self, port: u16, version: ProxyProtocol) -> &mut Config {
+        self.proxy_listeners.push((port, version));
+        self
+    }
+
+    /// Trusts the forwarding headers sent by peers in these CIDRs.
+    ///
+    /// For requests from a trusted proxy, `remote_addr`, `scheme` and `host`
+    /// report the client as described by the RFC 7239 `Forwarded` header, or
+    /// failing that `X-Forwarded-For`, `-Proto`, `-Host` and `-Port`. The
+    /// headers are ignored for every other peer. The CIDRs are checked when
+    /// the server starts.
+    pub fn trusted_proxies<I, S>(&mut self, cidrs: I) -> &mut Config
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.trusted_proxies
+            .extend(cidrs.into_iter().map(|cidr| cidr.as_ref().to_string()));
+        self
+    }
+
+    /// Requires HTTP Basic credentials accepted by `authenticator` for
+    /// requests under `uri_prefix`.
+    ///
+    /// When several prefixes match a request the longest one applies.
+    /// Rejected requests get a 401 challenge for the realm set with
+    /// `authentication_domain`, and accepted ones carry a `RemoteUser`
+    /// extension.
+    pub fn basic_auth<A: Authenticator>(
+        &mut self,
+        uri_prefix: &str,
+        authenticator: A,
+    ) -> &mut Config {
+        self.basic_auth.push(BasicAuth {
+            prefix: uri_prefix.to_string(),
+            authenticator: Box::new(authenticator),
+        });
+        self
+    }
+
+    /// The largest request body, in bytes, that the handler may read.
+    ///
+    /// Requests whose `Content-Length` is larger are answered with 413
+    /// Payload Too Large without calling the handler, and reading a chunked
+    /// body past the limit fails with `InvalidData`. A chunked body is
+    /// measured without its chunk framing.
+    pub fn max_request_size(&mut self, bytes: u64) -> &mut Config {
+        self.max_request_size = Some(bytes);
+        self
+    }
+
+    /// A directory of `error<code>.htm` templates used for errors civetweb
+    /// generates itself.
+    ///
+    /// Pages rendered by `ErrorHandler::http_error` take precedence.
+    pub fn error_pages<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.remove_option("error_pages");
+        self.error_pages = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Hides files matching a civetweb glob `pattern` such as `**.htpasswd`.
+    pub fn hide_files_pattern(&mut self, pattern: &str) -> &mut Config {
+        self.remove_option("hide_files_patterns");
+        self.hide_files_patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Passes an option straight through to civetweb.
+    ///
+    /// Option names are checked against civetweb's option table when the
+    /// server starts, which fails with `InvalidInput` on an unknown name.
+    /// Setting an option replaces any value set for it before, whether raw
+    /// or through a typed setter; a typed setter called afterwards replaces
+    /// it in turn. A raw `listening_ports` replaces both `port` and
+    /// `https_port`.
+    pub fn option(&mut self, name: &str, value: &str) -> &mut Config {
+        self.clear_typed(name);
+        self.remove_option(name);
+        self.options.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The raw value set for the civetweb option `name`, if any.
+    pub(crate) fn raw_option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    fn remove_option(&mut self, name: &str) {
+        self.options.retain(|(n, _)| n != name);
+    }
+
+    /// Forgets what the typed setters for the civetweb option `name` set.
+    fn clear_typed(&mut self, name: &str) {
+        match name {
+            "listening_ports" => {
+                self.port = None;
+                self.https_ports.clear();
+            }
+            "ssl_certificate" => self.ssl_certificate = None,
+            "num_threads" => self.threads = None,
+            "enable_keep_alive" => self.enable_keep_alive = None,
+            "request_timeout_ms" => self.request_timeout = None,
+            "throttle" => self.throttle.clear(),
+            "access_control_list" => self.access_control_list.clear(),
+            "authentication_domain" => self.authentication_domain = None,
+            "global_auth_file" => self.global_auth_file = None,
+            "protect_uri" => self.protect_uri.clear(),
+            "error_pages" => self.error_pages = None,
+            "hide_files_patterns" => self.hide_files_patterns.clear(),
+            _ => {}
+        }
+    }
+
+    /// Sets the hook that renders responses for failed requests.
+    ///
+    /// Defaults to `DefaultErrorHandler`, which sends an empty 500.
+    pub fn error_handler<H: ErrorHandler>(&mut self, handler: H) -> &mut Config {
+        self.error_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Sets a hook that is called with the details of every handler panic.
+    ///
+    /// Whether or not a reporter is set, a panicking handler results in a 500
+    /// response and the connection being closed.
+    pub fn panic_reporter<R: PanicReporter>(&mut self, reporter: R) -> &mut Config {
+        self.panic_reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// The body of the 400 response sent when request headers cannot be
+    /// parsed. Defaults to an empty body.
+    pub fn bad_request_body<B: Into<Vec<u8>>>(&mut self, body: B) -> &mut Config {
+        self.bad_request_body = Some(body.into());
+        self
+    }
+
+    /// The header that carries a `RequestId` in and out, instead of
+    /// `X-Request-Id`.
+    pub fn request_id_header(&mut self, name: &str) -> &mut Config {
+        self.request_id_header = Some(name.to_ascii_lowercase());
+        self
+    }
+
+    /// Sets the hooks called as connections open and close and as requests
+    /// begin and end.
+    pub fn hooks<H: ServerHooks>(&mut self, hooks: H) -> &mut Config {
+        self.hooks = Some(Box::new(hooks));
+        self
+    }
+
+    /// Sets an option from its civetweb name and textual value.
+    ///
+    /// Options with a typed setter are parsed and validated here, such as
+    /// `num_threads` or `request_timeout_ms`. Any other name is passed through
+    /// as with `Config::option`. A value replaces the one set before, and a
+    /// list such as `protect_uri` replaces every earlier rule.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<&mut Config, ConfigError> {
+        let value = value.trim();
+        let invalid = |message: &str| ConfigError::invalid(name, message);
+        match name {
+            "listening_ports" => match value.parse() {
+                Ok(port) => self.port(port),
+                Err(_) => self.option(name, value),
+            },
+            "num_threads" => self.threads(value.parse().map_err(|_| invalid("expected a number"))?),
+            "enable_keep_alive" => {
+                self.keep_alive(parse_bool(value).ok_or_else(|| invalid("expected yes or no"))?)
+            }
+            "request_timeout_ms" | "keep_alive_timeout_ms" | "linger_timeout_ms" => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| invalid("expected a number of milliseconds"))?;
+                let timeout = Duration::from_millis(ms);
+                match name {
+                    "request_timeout_ms" => self.request_timeout(timeout),
+                    "keep_alive_timeout_ms" => self.keep_alive_timeout(timeout),
+                    _ => self.linger_timeout(timeout),
+                }
+            }
+            "tcp_nodelay" => {
+                self.tcp_nodelay(parse_bool(value).ok_or_else(|| invalid("expected 1 or 0"))?)
+            }
+            "max_request_size" => self.max_request_size(
+                value
+                    .parse()
+                    .map_err(|_| invalid("expected a number of bytes"))?,
+            ),
+            "decompress_requests" => self
+                .decompress_requests(parse_bool(value).ok_or_else(|| invalid("expected 1 or 0"))?),
+            "max_decoded_body_size" => self.max_decoded_body_size(
+                value
+                    .parse()
+                    .map_err(|_| invalid("expected a number of bytes"))?,
+            ),
+            "authentication_domain" => self.authentication_domain(value),
+            "global_auth_file" => self.global_auth_file(value),
+            "protect_uri" => {
+                self.protect_uri.clear();
+                for rule in value.split(',') {
+                    let mut parts = rule.splitn(2, '=');
+                    let uri = parts.next().unwrap_or_default().trim();
+                    match parts.next() {
+                        Some(file) if !uri.is_empty() => self.protect_uri(uri, file.trim()),
+                        _ => return Err(invalid("expected uri=file pairs")),
+                    };
+                }
+                &mut *self
+            }
+            "error_pages" => self.error_pages(value),
+            "trusted_proxies" => {
+                self.trusted_proxies.clear();
+                self.trusted_proxies(value.split(',').map(str::trim))
+            }
+            _ if value.is_empty() => return Err(invalid("missing value")),
+            _ => self.option(name, value),
+        };
+        Ok(self)
+    }
+
+    /// Loads a configuration file.
+    ///
+    /// Files ending in `.toml` are read as a TOML table of civetweb options,
+    /// which requires the `toml` feature. Any other file is read in
+    /// civetweb's native format of one `name value` pair per line, with `#`
+    /// starting a comment line.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError {
+            key: None,
+            location: Some(path.display().to_string()),
+            message: e.to_string(),
+        })?;
+
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            return Config::from_toml(&text).map_err(|mut e| {
+                e.location = Some(path.display().to_string());
+                e
+            });
+        }
+
+        let mut config = Config::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            config.set(name, value).map_err(|mut e| {
+                e.location = Some(format!("{}:{}", path.display(), i + 1));
+                e
+            })?;
+        }
+        Ok(config)
+    }
+
+    #[cfg(feature = "toml")]
+    fn from_toml(text: &str) -> Result<Config, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError {
+            key: None,
+            location: None,
+            message: e.to_string(),
+        })
+    }
+
+    #[cfg(not(feature = "toml"))]
+    fn from_toml(_text: &str) -> Result<Config, ConfigError> {
+        Err(ConfigError {
+            key: None,
+            location: None,
+            message: "TOML configuration requires the `toml` feature".to_string(),
+        })
+    }
+
+    /// Builds a configuration from environment variables starting with
+    /// `prefix`.
+    ///
+    /// The rest of each variable name is lowercased to give the civetweb
+    /// option name, so with a prefix of `CIVET_` the variable
+    /// `CIVET_NUM_THREADS` sets `num_threads`.
+    pub fn from_env(prefix: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::new();
+        config.apply_env(prefix)?;
+        Ok(config)
+    }
+
+    /// Applies environment variables starting with `prefix` on top of this
+    /// configuration, as described in `Config::from_env`.
+    pub fn apply_env(&mut self, prefix: &str) -> Result<&mut Config, ConfigError> {
+        let mut vars = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+            .filter(|(name, _)| name.starts_with(prefix) && name.len() > prefix.len())
+            .collect::<Vec<_>>();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (var, value) in vars {
+            let name = var[prefix.len()..].to_ascii_lowercase();
+            let value = value
+                .into_string()
+                .map_err(|_| ConfigError::invalid(&name, "value is not valid unicode"));
+            value
+                .and_then(|value| self.set(&name, &value).map(|_| ()))
+                .map_err(|mut e| {
+                    e.location = Some(format!("environment variable {}", var));
+                    e
+                })?;
+        }
+        Ok(self)
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match &value.to_ascii_lowercase()[..] {
+        "yes" | "true" | "on" | "1" => Some(true),
+        "no" | "false" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// An error loading a `Config`, naming the offending option where known.
+#[derive(Debug)]
+pub struct ConfigError {
+    key: Option<String>,
+    location: Option<String>,
+    message: String,
+}
+
+impl ConfigError {
+    fn invalid(key: &str, message: &str) -> ConfigError {
+        ConfigError {
+            key: Some(key.to_string()),
+            location: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// The name of the option that could not be loaded
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "invalid value for `{}`: ", key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Deserializes a map of civetweb option names to values, using the same
+/// parsing as `Config::set`. Lists are joined with commas.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Config {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Config, D::Error> {
+        use serde::de::{self, MapAccess, SeqAccess, Visitor};
+
+        struct ConfigVisitor;
+
+        impl<'de> Visitor<'de> for ConfigVisitor {
+            type Value = Config;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a map of civetweb options")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Config, A::Error> {
+                let mut config = Config::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    let OptionValue(value) = map.next_value()?;
+                    config.set(&name, &value).map_err(de::Error::custom)?;
+                }
+                Ok(config)
+            }
+        }
+
+        struct OptionValue(String);
+
+        impl<'de> serde::Deserialize<'de> for OptionValue {
+            fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<OptionValue, D::Error> {
+                d.deserialize_any(OptionValueVisitor)
+            }
+        }
+
+        struct OptionValueVisitor;
+
+        impl<'de> Visitor<'de> for OptionValueVisitor {
+            type Value = OptionValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string, number, boolean or list")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<OptionValue, E> {
+                Ok(OptionValue(v.to_string()))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<OptionValue, E> {
+                Ok(OptionValue((if v { "yes" } else { "no" }).to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<OptionValue, E> {
+                Ok(OptionValue(v.to_string()))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<OptionValue, E> {
+                Ok(OptionValue(v.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OptionValue, A::Error> {
+                let mut items = Vec::new();
+                while let Some(OptionValue(item)) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(OptionValue(items.join(",")))
+            }
+        }
+
+        deserializer.deserialize_map(ConfigVisitor)
+    }
 }
 
-pub fn config_to_options(config: &Config) -> (Vec<CString>, Vec<*const c_char>) {
+pub fn config_to_options(config: &Config) -> io::Result<(Vec<CString>, Vec<*const c_char>)> {
     let Config {
         port,
+        ref https_ports,
+        redirect_listeners: _,
+        ref ssl_certificate,
+        #[cfg(feature = "tls")]
+            sni_certificates: _,
         threads,
         enable_keep_alive,
+        request_timeout,
+        keep_alive_timeout,
+        linger_timeout,
+        tcp_nodelay,
+        ref throttle,
+        ref access_control_list,
+        ref authentication_domain,
+        ref global_auth_file,
+        ref protect_uri,
+        max_request_size: _,
+        ref error_pages,
+        ref hide_files_patterns,
+        ref options,
+        error_handler: _,
+        panic_reporter: _,
+        bad_request_body: _,
+        request_id_header: _,
+        hooks: _,
+        basic_auth: _,
+        access_control: _,
+        trusted_proxies: _,
+        proxy_listeners: _,
+        loopback,
+        compression: _,
+        decompress_requests: _,
+        max_decoded_body_size: _,
+        metrics: _,
+        security_headers: _,
     } = *config;
-    let mut options = Vec::new();
-    opt(&mut options, "listening_ports", port.map(|i| i.to_string()));
-    opt(&mut options, "num_threads", threads.map(|i| i.to_string()));
+    let unsupported = [
+        ("keep_alive_timeout", keep_alive_timeout.is_some()),
+        ("linger_timeout", linger_timeout.is_some()),
+        ("tcp_nodelay", tcp_nodelay.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("civetweb 1.6 does not support `{}`", name),
+        ));
+    }
+    let mut opts = Vec::new();
+    opt(
+        &mut opts,
+        "listening_ports",
+        list(
+            config
+                .raw_option("listening_ports")
+                .map(str::to_string)
+                .into_iter()
+                .chain(port.map(|i| i.to_string()))
+                .chain(https_ports.iter().map(|i| format!("{}s", i)))
+                .chain(Some("127.0.0.1:0".to_string()).filter(|_| loopback)),
+        ),
+    )?;
     opt(
-        &mut options,
+        &mut opts,
+        "ssl_certificate",
+        ssl_certificate
+            .as_ref()
+            .map(|file| file.display().to_string()),
+    )?;
+    opt(&mut opts, "num_threads", threads.map(|i| i.to_string()))?;
+    opt(
+        &mut opts,
         "enable_keep_alive",
-        enable_keep_alive.map(|b| (if b { "yes" } else { "no" }).to_string()),
-    );
-    let mut ptrs: Vec<*const c_char> = options.iter().map(|a| a.as_ptr()).collect();
+        enable_keep_alive.map(yes_no),
+    )?;
+    opt(&mut opts, "request_timeout_ms", request_timeout.map(millis))?;
+    opt(
+        &mut opts,
+        "throttle",
+        list(
+            throttle
+                .iter()
+                .map(|(target, limit)| format!("{}={}", target, limit)),
+        ),
+    )?;
+    opt(
+        &mut opts,
+        "access_control_list",
+        list(
+            access_control_list
+                .iter()
+                .map(|(allow, subnet)| format!("{}{}", if *allow { '+' } else { '-' }, subnet)),
+        ),
+    )?;
+    opt(
+        &mut opts,
+        "authentication_domain",
+        authentication_domain.clone(),
+    )?;
+    opt(
+        &mut opts,
+        "global_auth_file",
+        global_auth_file
+            .as_ref()
+            .map(|file| file.display().to_string()),
+    )?;
+    opt(
+        &mut opts,
+        "protect_uri",
+        list(
+            protect_uri
+                .iter()
+                .map(|(uri, file)| format!("{}={}", uri, file.display())),
+        ),
+    )?;
+    opt(
+        &mut opts,
+        "error_pages",
+        error_pages.as_ref().map(|dir| {
+            // civetweb appends the file name directly to this prefix
+            let mut dir = dir.display().to_string();
+            if !dir.ends_with(MAIN_SEPARATOR) && !dir.ends_with('/') {
+                dir.push(MAIN_SEPARATOR);
+            }
+            dir
+        }),
+    )?;
+    opt(
+        &mut opts,
+        "hide_files_patterns",
+        Some(hide_files_patterns.join("|")).filter(|s| !s.is_empty()),
+    )?;
+    // A raw `listening_ports` was merged with the typed listeners above
+    for (name, value) in options.iter().filter(|(name, _)| name != "listening_ports") {
+        opt(&mut opts, name, Some(value.clone()))?;
+    }
+    let mut ptrs: Vec<*const c_char> = opts.iter().map(|a| a.as_ptr()).collect();
     ptrs.push(std::ptr::null::<c_char>());
-    return (options, ptrs);
+    return Ok((opts, ptrs));
 
-    fn opt(v: &mut Vec<CString>, name: &str, opt: Option<String>) {
+    fn yes_no(b: bool) -> String {
+        (if b { "yes" } else { "no" }).to_string()
+    }
+
+    fn millis(d: Duration) -> String {
+        (d.as_secs() * 1000 + u64::from(d.subsec_millis())).to_string()
+    }
+
+    fn list<I: Iterator<Item = String>>(items: I) -> Option<String> {
+        Some(items.collect::<Vec<_>>().join(",")).filter(|s| !s.is_empty())
+    }
+
+    fn opt(v: &mut Vec<CString>, name: &str, opt: Option<String>) -> io::Result<()> {
         if let Some(t) = opt {
-            v.push(CString::new(name).unwrap());
-            v.push(CString::new(t).unwrap());
+            let invalid = |_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("civetweb option `{}` contains a nul byte", name),
+                )
+            };
+            v.push(CString::new(name).map_err(invalid)?);
+            v.push(CString::new(t).map_err(invalid)?);
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_to_options, Config};
+    use std::fs;
+    use std::io;
+    use std::time::Duration;
+
+    fn options(config: &Config) -> Vec<String> {
+        let (opts, ptrs) = config_to_options(config).unwrap();
+        assert!(ptrs.last().unwrap().is_null());
+        opts.into_iter().map(|s| s.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn typed_options() {
+        let mut config = Config::new();
+        config
+            .port(8888)
+            .request_timeout(Duration::from_millis(1500))
+            .throttle("*", 1024)
+            .throttle("/downloads/", 0)
+            .deny_subnet("0.0.0.0/0")
+            .allow_subnet("10.0.0.0/8")
+            .protect_uri("/admin/", "/etc/civet/admin.htpasswd")
+            .protect_uri("/ops/", "/etc/civet/ops.htpasswd")
+            .error_pages("/srv/errors")
+            .hide_files_pattern("**.htpasswd")
+            .hide_files_pattern("**.git/")
+            .option("document_root", "/srv/www");
+        assert_eq!(
+            options(&config),
+            vec![
+                "listening_ports",
+                "8888",
+                "request_timeout_ms",
+                "1500",
+                "throttle",
+                "*=1024,/downloads/=0",
+                "access_control_list",
+                "-0.0.0.0/0,+10.0.0.0/8",
+                "protect_uri",
+                "/admin/=/etc/civet/admin.htpasswd,/ops/=/etc/civet/ops.htpasswd",
+                "error_pages",
+                "/srv/errors/",
+                "hide_files_patterns",
+                "**.htpasswd|**.git/",
+                "document_root",
+                "/srv/www",
+            ]
+        );
+    }
+
+    #[test]
+    fn nul_bytes_are_rejected() {
+        let mut config = Config::new();
+        config.option("document_root", "/srv\0");
+        assert!(config_to_options(&config).is_err());
+    }
+
+    #[test]
+    fn unsupported_timeouts_are_rejected() {
+        let mut config = Config::new();
+        config.set("keep_alive_timeout_ms", "500").unwrap();
+        let err = config_to_options(&config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("keep_alive_timeout"), "{}", err);
+
+        let mut config = Config::new();
+        config.linger_timeout(Duration::from_secs(2));
+        let err = config_to_options(&config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn tcp_nodelay_is_rejected() {
+        let mut config = Config::new();
+        config.set("tcp_nodelay", "1").unwrap();
+        let err = config_to_options(&config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("tcp_nodelay"), "{}", err);
+    }
+
+    #[test]
+    fn raw_options_replace_typed_ones() {
+        let mut config = Config::new();
+        config
+            .port(8080)
+            .https_port(8443)
+            .threads(2)
+            .protect_uri("/a/", "a.htpasswd")
+            .option("listening_ports", "9090")
+            .option("num_threads", "3")
+            .option("protect_uri", "/b/=b.htpasswd");
+        config.loopback = true;
+        assert_eq!(
+            options(&config),
+            vec![
+                "listening_ports",
+                "9090,127.0.0.1:0",
+                "num_threads",
+                "3",
+                "protect_uri",
+                "/b/=b.htpasswd",
+            ]
+        );
+    }
+
+    #[test]
+    fn native_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("civet.conf");
+        fs::write(
+            &path,
+            "# comment\n\nlistening_ports 8080\nnum_threads  4\ndocument_root /srv/www\n",
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(
+            options(&config),
+            vec![
+                "listening_ports",
+                "8080",
+                "num_threads",
+                "4",
+                "document_root",
+                "/srv/www",
+            ]
+        );
+
+        fs::write(&path, "num_threads 4\nrequest_timeout_ms soon\n").unwrap();
+        let err = Config::from_file(&path).err().unwrap();
+        assert_eq!(err.key(), Some("request_timeout_ms"));
+        let msg = err.to_string();
+        assert!(msg.contains("civet.conf:2"), "{}", msg);
+        assert!(msg.contains("`request_timeout_ms`"), "{}", msg);
+    }
+
+    #[test]
+    fn env_overrides() {
+        std::env::set_var("CIVET_TEST_NUM_THREADS", "2");
+        std::env::set_var("CIVET_TEST_ENABLE_KEEP_ALIVE", "yes");
+        let mut config = Config::new();
+        config.threads(1);
+        config.apply_env("CIVET_TEST_").unwrap();
+        assert_eq!(
+            options(&config),
+            vec!["num_threads", "2", "enable_keep_alive", "yes"]
+        );
+
+        std::env::set_var("CIVET_TEST_BAD_NUM_THREADS", "many");
+        let err = Config::from_env("CIVET_TEST_BAD_").err().unwrap();
+        assert_eq!(err.key(), Some("num_threads"));
+        assert!(err.to_string().contains("CIVET_TEST_BAD_NUM_THREADS"));
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("civet.conf");
+        fs::write(
+            &path,
+            "throttle *=1k\nhide_files_patterns **.git/\nlistening_ports 80,443s\n\
+             protect_uri /a/=a.htpasswd\ndocument_root /srv/a\n",
+        )
+        .unwrap();
+        let mut config = Config::from_file(&path).unwrap();
+        config
+            .set("protect_uri", "/b/=b.htpasswd")
+            .unwrap()
+            .set("document_root", "/srv/b")
+            .unwrap();
+        config
+            .throttle("*", 2048)
+            .hide_files_pattern("**.htpasswd")
+            .port(8080)
+            .threads(2)
+            .option("num_threads", "3");
+        assert_eq!(
+            options(&config),
+            vec![
+                "listening_ports",
+                "8080",
+                "throttle",
+                "*=2048",
+                "protect_uri",
+                "/b/=b.htpasswd",
+                "hide_files_patterns",
+                "**.htpasswd",
+                "document_root",
+                "/srv/b",
+                "num_threads",
+                "3",
+            ]
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("civet.toml");
+        fs::write(
+            &path,
+            "listening_ports = 8080\nenable_keep_alive = true\nhide_files_patterns = \"**.git/\"\n",
+        )
+        .unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(
+            options(&config),
+            vec![
+                "listening_ports",
+                "8080",
+                "enable_keep_alive",
+                "yes",
+                "hide_files_patterns",
+                "**.git/",
+            ]
+        );
+
+        fs::write(&path, "num_threads = \"lots\"\n").unwrap();
+        let err = Config::from_file(&path).err().unwrap().to_string();
+        assert!(err.contains("`num_threads`"), "{}", err);
     }
 }
@@ -0,0 +1,408 @@
+use std::time::Duration;
+
+use conduit::header::{self, HeaderMap, HeaderValue};
+use conduit::{Body, Method, RequestExt, Response, StatusCode};
+
+use layer::Layer;
+
+const ANY_ORIGIN_WITH_CREDENTIALS: &str = "CORS credentials cannot be allowed for any origin";
+
+/// A `Layer` that implements CORS.
+///
+/// Preflight `OPTIONS` requests are answered without calling the handler:
+/// with 204 if the origin, method and headers are all allowed, and with 403
+/// otherwise. Other requests from an allowed origin get the
+/// `Access-Control-Allow-*` headers added to their response, unless the
+/// handler set `Access-Control-Allow-Origin` itself.
+///
+/// Origins are exact, such as `https://example.com`, or cover subdomains,
+/// such as `https://*.example.com`. The methods default to `GET`, `HEAD` and
+/// `POST`, and no request headers beyond the CORS-safelisted ones are
+/// allowed until `allow_header` is called.
+///
+/// Layers run after the server's authentication, so a preflight to a path
+/// covered by `Config::basic_auth`, `global_auth_file` or `protect_uri` is
+/// answered with 401 before it reaches this layer, as browsers never send
+/// credentials with one. Leave the paths scripts call across origins out
+/// of those rules and check credentials in the handler instead.
+///
+/// ```
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use std::time::Duration;
+/// use civet::{Cors, Server};
+/// use conduit::Method;
+///
+/// let mut cors = Cors::new();
+/// cors.allow_origin("https://example.com")
+///     .allow_origin("https://*.example.com")
+///     .allow_methods(vec![Method::GET, Method::PUT, Method::DELETE])
+///     .allow_header("content-type")
+///     .allow_credentials(true)
+///     .max_age(Duration::from_secs(600));
+///
+/// let mut builder = Server::builder();
+/// builder.layer(cors);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: Vec<Origin>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    any_header: bool,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+enum Origin {
+    Any,
+    Exact(String),
+    /// The scheme with `://`, and the domain suffix with its leading dot.
+    Subdomains(String, String),
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Vec::new(),
+            any_header: false,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows an origin such as `https://example.com`. A `*` in place of
+    /// the first label allows every subdomain, and `*` on its own allows
+    /// any origin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `*` is given and credentials are allowed, as that would let
+    /// every site make credentialed requests.
+    pub fn allow_origin(&mut self, origin: &str) -> &mut Cors {
+        let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+        let parsed = if origin == "*" {
+            assert!(!self.credentials, "{}", ANY_ORIGIN_WITH_CREDENTIALS);
+            Origin::Any
+        } else {
+            match origin.find("://*.") {
+                Some(i) => {
+                    Origin::Subdomains(origin[..i + 3].to_string(), origin[i + 4..].to_string())
+                }
+                None => Origin::Exact(origin),
+            }
+        };
+        self.origins.push(parsed);
+        self
+    }
+
+    pub fn allow_any_origin(&mut self) -> &mut Cors {
+        self.allow_origin("*")
+    }
+
+    /// Replaces the allowed methods.
+    pub fn allow_methods<I: IntoIterator<Item = Method>>(&mut self, methods: I) -> &mut Cors {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Allows a request header. `*` allows any header the client asks for.
+    pub fn allow_header(&mut self, name: &str) -> &mut Cors {
+        if name.trim() == "*" {
+            self.any_header = true;
+        } else {
+            self.headers.push(name.trim().to_ascii_lowercase());
+        }
+        self
+    }
+
+    /// Lets scripts read a response header beyond the CORS-safelisted ones.
+    pub fn expose_header(&mut self, name: &str) -> &mut Cors {
+        self.expose_headers.push(name.trim().to_ascii_lowercase());
+        self
+    }
+
+    /// Allows cookies and HTTP authentication.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any origin is allowed, as that would let every site make
+    /// credentialed requests.
+    pub fn allow_credentials(&mut self, credentials: bool) -> &mut Cors {
+        let any = self.origins.iter().any(|o| matches!(o, Origin::Any));
+        assert!(!(credentials && any), "{}", ANY_ORIGIN_WITH_CREDENTIALS);
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight response.
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        self.origins.iter().any(|allowed| match allowed {
+            Origin::Any => true,
+            Origin::Exact(exact) => *exact == origin,
+            Origin::Subdomains(scheme, suffix) => {
+                origin.starts_with(&scheme[..])
+                    && origin.ends_with(&suffix[..])
+                    && origin.len() > scheme.len() + suffix.len()
+            }
+        })
+    }
+
+    /// Whether every origin is allowed, so that `*` can be sent.
+    fn is_public(&self) -> bool {
+        self.origins.iter().any(|o| matches!(o, Origin::Any))
+    }
+
+    /// Adds the headers common to preflight and actual responses.
+    fn allow(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        if self.is_public() {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(&self, origin: &HeaderValue, req: &dyn RequestExt) -> Response<Body> {
+        let request_headers = req.headers();
+        let method = request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Method>().ok());
+        let requested = request_headers
+            .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>();
+
+        let allowed = origin.to_str().is_ok_and(|origin| self.allows(origin))
+            && method.is_some_and(|method| self.methods.contains(&method))
+            && (self.any_header || requested.iter().all(|name| self.headers.contains(name)));
+
+        let mut response = Response::new(Body::empty());
+        if !allowed {
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_LENGTH, 0.into());
+            vary_origin(headers);
+            return response;
+        }
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let headers = response.headers_mut();
+        vary_origin(headers);
+        self.allow(origin, headers);
+        let methods = self
+            .methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allow_headers = if self.any_header {
+            requested.join(", ")
+        } else {
+            self.headers.join(", ")
+        };
+        if !allow_headers.is_empty() {
+            if let Ok(allow_headers) = HeaderValue::from_str(&allow_headers) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+        response
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+impl Layer for Cors {
+    fn before(&self, req: &mut dyn RequestExt) -> Option<Response<Body>> {
+        if *req.method() != Method::OPTIONS
+            || !req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let origin = req.headers().get(header::ORIGIN)?.clone();
+        Some(self.preflight(&origin, req))
+    }
+
+    fn after(&self, req: &mut dyn RequestExt, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        if headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+        if !self.is_public() {
+            vary_origin(headers);
+        }
+        let origin = match req.headers().get(header::ORIGIN) {
+            Some(origin) => origin,
+            None => return,
+        };
+        if !origin.to_str().is_ok_and(|origin| self.allows(origin)) {
+            return;
+        }
+        self.allow(origin, headers);
+        if !self.expose_headers.is_empty() {
+            if let Ok(expose) = HeaderValue::from_str(&self.expose_headers.join(", ")) {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+            }
+        }
+    }
+}
+
+/// Marks a response as depending on the request's `Origin`.
+fn vary_origin(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("origin")
+        });
+    if !varies {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use conduit::{header, Body, Method, Response, StatusCode};
+
+    use super::Cors;
+    use layer::test::MockRequest;
+    use layer::Layer;
+
+    fn cors() -> Cors {
+        let mut cors = Cors::new();
+        cors.allow_origin("https://example.com")
+            .allow_origin("https://*.example.org")
+            .allow_methods(vec![Method::GET, Method::PUT])
+            .allow_header("Content-Type")
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600));
+        cors
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> Option<Response<Body>> {
+        let mut req = MockRequest::new(Method::OPTIONS, "/");
+        req.headers.insert(header::ORIGIN, origin.parse().unwrap());
+        req.headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            method.parse().unwrap(),
+        );
+        req.headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            headers.parse().unwrap(),
+        );
+        cors().before(&mut req)
+    }
+
+    #[test]
+    fn answers_preflight() {
+        let response = preflight("https://api.example.org", "PUT", "content-type").unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://api.example.org"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        for &(origin, method, headers) in &[
+            ("https://example.org", "PUT", ""),
+            ("https://evil.com", "PUT", ""),
+            ("https://example.com", "DELETE", ""),
+            ("https://example.com", "GET", "x-secret"),
+        ] {
+            let response = preflight(origin, method, headers).unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", origin);
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+    }
+
+    #[test]
+    fn adds_headers_to_responses() {
+        let cors = cors();
+        let mut req = MockRequest::new(Method::GET, "/");
+        assert!(cors.before(&mut req).is_none());
+
+        let mut response = Response::new(Body::empty());
+        cors.after(&mut req, &mut response);
+        assert_eq!(response.headers()[header::VARY], "Origin");
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        req.headers
+            .insert(header::ORIGIN, "https://example.com".parse().unwrap());
+        let mut response = Response::new(Body::empty());
+        cors.after(&mut req, &mut response);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+
+        let mut public = Cors::new();
+        public.allow_any_origin();
+        let mut response = Response::new(Body::empty());
+        public.after(&mut req, &mut response);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!response.headers().contains_key(header::VARY));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be allowed for any origin")]
+    fn refuses_credentials_for_any_origin() {
+        Cors::new().allow_credentials(true).allow_any_origin();
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be allowed for any origin")]
+    fn refuses_any_origin_with_credentials() {
+        Cors::new().allow_origin("*").allow_credentials(true);
+    }
+}
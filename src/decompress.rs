@@ -0,0 +1,201 @@
+use std::io::{self, Read};
+
+use conduit::{header, Body, HeaderMap, Response, StatusCode};
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+/// A `Content-Encoding` the server can decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Coding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+/// The coding a request body must be decoded with.
+///
+/// Returns `Err` for a coding the server cannot decode, including a stack
+/// of several codings.
+pub(crate) fn coding(headers: &HeaderMap) -> Result<Option<Coding>, ()> {
+    let mut codings = headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .map(|value| value.to_str().map_err(|_| ()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity");
+
+    let coding = match codings.next() {
+        Some(coding) => coding,
+        None => return Ok(None),
+    };
+    if codings.next().is_some() {
+        return Err(());
+    }
+    match &coding[..] {
+        "gzip" | "x-gzip" => Ok(Some(Coding::Gzip)),
+        "deflate" => Ok(Some(Coding::Deflate)),
+        #[cfg(feature = "brotli")]
+        "br" => Ok(Some(Coding::Brotli)),
+        _ => Err(()),
+    }
+}
+
+enum Inner<R: Read> {
+    Identity(R),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::Decompressor<R>>),
+    Gzip(GzDecoder<R>),
+    Deflate(ZlibDecoder<R>),
+}
+
+/// Decodes a request body, failing once the decoded bytes exceed a limit.
+pub(crate) struct Decoder<R: Read> {
+    inner: Inner<R>,
+    remaining: u64,
+    limit: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    pub(crate) fn new(inner: R, coding: Option<Coding>, limit: u64) -> Decoder<R> {
+        let inner = match coding {
+            None => Inner::Identity(inner),
+            #[cfg(feature = "brotli")]
+            Some(Coding::Brotli) => Inner::Brotli(Box::new(brotli::Decompressor::new(inner, 4096))),
+            Some(Coding::Gzip) => Inner::Gzip(GzDecoder::new(inner)),
+            Some(Coding::Deflate) => Inner::Deflate(ZlibDecoder::new(inner)),
+        };
+        Decoder {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+
+    /// Whether the body is being decoded, so that its length is unknown.
+    pub(crate) fn is_decoding(&self) -> bool {
+        !matches!(self.inner, Inner::Identity(_))
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        match self.inner {
+            Inner::Identity(ref r) => r,
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref r) => r.get_ref(),
+            Inner::Gzip(ref r) => r.get_ref(),
+            Inner::Deflate(ref r) => r.get_ref(),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        match self.inner {
+            Inner::Identity(ref mut r) => r,
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut r) => r.get_mut(),
+            Inner::Gzip(ref mut r) => r.get_mut(),
+            Inner::Deflate(ref mut r) => r.get_mut(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.is_decoding() {
+            return match self.inner {
+                Inner::Identity(ref mut r) => r.read(buf),
+                _ => unreachable!(),
+            };
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Read one byte past the limit to tell a body that ends exactly at
+        // the limit from one that goes over it
+        let max = std::cmp::min(buf.len() as u64, self.remaining.saturating_add(1)) as usize;
+        let n = match self.inner {
+            Inner::Identity(_) => unreachable!(),
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut r) => r.read(&mut buf[..max])?,
+            Inner::Gzip(ref mut r) => r.read(&mut buf[..max])?,
+            Inner::Deflate(ref mut r) => r.read(&mut buf[..max])?,
+        };
+        if n as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decoded request body exceeds {} bytes", self.limit),
+            ));
+        }
+        if n == 0 {
+            // Consume anything after the compressed stream so that the raw
+            // body is read to its end
+            io::copy(self.get_mut(), &mut io::sink())?;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// The response to a request body in a coding the server cannot decode.
+pub(crate) fn unsupported_media_type() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, 0.into());
+    let accepted = if cfg!(feature = "brotli") {
+        "gzip, deflate, br"
+    } else {
+        "gzip, deflate"
+    };
+    headers.insert(
+        header::ACCEPT_ENCODING,
+        header::HeaderValue::from_static(accepted),
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::{coding, Coding, Decoder};
+    use conduit::{header, HeaderMap};
+    use flate2::write::GzEncoder;
+    use std::io::{Read, Write};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn picks_coding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(coding(&headers), Ok(None));
+        headers.insert(header::CONTENT_ENCODING, "identity".parse().unwrap());
+        assert_eq!(coding(&headers), Ok(None));
+        headers.insert(header::CONTENT_ENCODING, "GZIP".parse().unwrap());
+        assert_eq!(coding(&headers), Ok(Some(Coding::Gzip)));
+        headers.insert(header::CONTENT_ENCODING, "gzip, deflate".parse().unwrap());
+        assert_eq!(coding(&headers), Err(()));
+        headers.insert(header::CONTENT_ENCODING, "compress".parse().unwrap());
+        assert_eq!(coding(&headers), Err(()));
+    }
+
+    #[test]
+    fn decodes_within_limit() {
+        let body = gzip(b"{\"hello\":\"world\"}");
+        let mut decoder = Decoder::new(&body[..], Some(Coding::Gzip), 17);
+        let mut decoded = String::new();
+        decoder.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "{\"hello\":\"world\"}");
+    }
+
+    #[test]
+    fn limits_decoded_size() {
+        let body = gzip(&[0; 1 << 20]);
+        let mut decoder = Decoder::new(&body[..], Some(Coding::Gzip), 1000);
+        let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
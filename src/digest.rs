@@ -0,0 +1,330 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use conduit::{header, Body, HeaderMap, Method, RequestExt, Response, StatusCode};
+
+use auth::RemoteUser;
+
+/// civetweb's default for `authentication_domain`
+const DEFAULT_REALM: &str = "mydomain.com";
+
+/// Digest authentication for `PUT` and `DELETE` requests.
+///
+/// civetweb 1.6 checks `global_auth_file` and `protect_uri` for every other
+/// method, but hands `PUT` and `DELETE` to the handler unchecked. These are
+/// checked here instead, against the same files and in the same order.
+pub(crate) struct DigestAuth {
+    realm: String,
+    global: Option<PathBuf>,
+    protected: Vec<(String, PathBuf)>,
+    first_nonce: u64,
+    issued: AtomicU64,
+}
+
+impl DigestAuth {
+    /// Returns `None` when no credential files are configured.
+    pub(crate) fn new(
+        realm: Option<&str>,
+        global: Option<PathBuf>,
+        protected: Vec<(String, PathBuf)>,
+    ) -> Option<DigestAuth> {
+        if global.is_none() && protected.is_empty() {
+            return None;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Some(DigestAuth {
+            realm: realm.unwrap_or(DEFAULT_REALM).to_string(),
+            global,
+            protected,
+            first_nonce: now,
+            issued: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the 401 challenge to send if the request is not allowed
+    /// through.
+    pub(crate) fn check(&self, request: &mut dyn RequestExt) -> Option<Response<Body>> {
+        if request.method() != Method::PUT && request.method() != Method::DELETE {
+            return None;
+        }
+        // Like civetweb, the first matching prefix wins over the global file
+        let file = self
+            .protected
+            .iter()
+            .find(|(prefix, _)| request.path().starts_with(prefix.as_str()))
+            .map(|(_, file)| file)
+            .or(self.global.as_ref())?;
+
+        if let Some(user) = self.authorize(request.method().as_str(), request.headers(), file) {
+            request.mut_extensions().insert(RemoteUser(user));
+            return None;
+        }
+        Some(self.unauthorized())
+    }
+
+    fn authorize(&self, method: &str, headers: &HeaderMap, file: &PathBuf) -> Option<String> {
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?.trim();
+        if value.len() < 7 || !value[..7].eq_ignore_ascii_case("digest ") {
+            return None;
+        }
+        let params = params(&value[7..]);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let user = param("username")?;
+        let nonce = param("nonce")?;
+        let issued = nonce.parse::<u64>().ok()?.checked_sub(self.first_nonce)?;
+        if issued >= self.issued.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        // Each line is `user:realm:md5(user:realm:password)`
+        let passwords = fs::read_to_string(file).ok()?;
+        let ha1 = passwords.lines().find_map(|line| {
+            let mut fields = line.trim_end().splitn(3, ':');
+            if fields.next()? == user && fields.next()? == self.realm {
+                fields.next()
+            } else {
+                None
+            }
+        })?;
+        let expected = response(
+            ha1,
+            method,
+            param("uri")?,
+            nonce,
+            param("nc")?,
+            param("cnonce")?,
+            param("qop")?,
+        );
+        if param("response")?.eq_ignore_ascii_case(&expected) {
+            Some(user.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn unauthorized(&self) -> Response<Body> {
+        let nonce = self.first_nonce + self.issued.fetch_add(1, Ordering::SeqCst);
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge = format!(
+            "Digest qop=\"auth\", realm=\"{}\", nonce=\"{}\"",
+            realm, nonce
+        );
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_LENGTH, 0.into());
+        // The request body is left unread
+        headers.insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("close"),
+        );
+        if let Ok(challenge) = header::HeaderValue::from_str(&challenge) {
+            headers.insert(header::WWW_AUTHENTICATE, challenge);
+        }
+        response
+    }
+}
+
+/// The `response` a client computes from the password hash `ha1`.
+fn response(
+    ha1: &str,
+    method: &str,
+    uri: &str,
+    nonce: &str,
+    nc: &str,
+    cnonce: &str,
+    qop: &str,
+) -> String {
+    let ha2 = hex(&md5(format!("{}:{}", method, uri).as_bytes()));
+    let response = format!("{}:{}:{}:{}:{}:{}", ha1, nonce, nc, cnonce, qop, ha2);
+    hex(&md5(response.as_bytes()))
+}
+
+/// Splits the `name=value` pairs of a `Digest` header, unquoting values.
+fn params(input: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|&c| c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if name.is_empty() {
+            return params;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ',').collect();
+        }
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// MD5, as digest authentication requires.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(input.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in message.chunks(64) {
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let k = ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32;
+            let f = f.wrapping_add(a).wrapping_add(k).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip(&[a, b, c, d]) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (out, word) in digest.chunks_mut(4).zip(&state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hex, md5, params, response, DigestAuth};
+    use conduit::{header, HeaderMap};
+    use std::fs;
+
+    #[test]
+    fn hashes_md5() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        let long =
+            "12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+        assert_eq!(
+            hex(&md5(long.as_bytes())),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn computes_responses() {
+        // The example from RFC 2617, section 3.5
+        let ha1 = hex(&md5(b"Mufasa:testrealm@host.com:Circle Of Life"));
+        assert_eq!(
+            response(
+                &ha1,
+                "GET",
+                "/dir/index.html",
+                "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+                "00000001",
+                "0a4f113b",
+                "auth"
+            ),
+            "6629fae49393a05397450978507c4ef1"
+        );
+    }
+
+    #[test]
+    fn authorizes_issued_nonces() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(".htpasswd");
+        let ha1 = hex(&md5(b"mufasa:zoo:lion"));
+        fs::write(&file, format!("other:zoo:x\nmufasa:zoo:{}\n", ha1)).unwrap();
+        let auth = DigestAuth::new(Some("zoo"), Some(file.clone()), Vec::new()).unwrap();
+
+        let authorization = |nonce: u64, ha1: &str| {
+            let nonce = nonce.to_string();
+            let response = response(ha1, "PUT", "/a", &nonce, "00000001", "c", "auth");
+            let mut headers = HeaderMap::new();
+            let value = format!(
+                "Digest username=\"mufasa\", realm=\"zoo\", nonce=\"{}\", uri=\"/a\", \
+                 qop=auth, nc=00000001, cnonce=\"c\", response=\"{}\"",
+                nonce, response
+            );
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        // Nothing has been issued yet
+        let nonce = auth.first_nonce;
+        assert_eq!(
+            auth.authorize("PUT", &authorization(nonce, &ha1), &file),
+            None
+        );
+        auth.unauthorized();
+        assert_eq!(
+            auth.authorize("PUT", &authorization(nonce, &ha1), &file),
+            Some("mufasa".to_string())
+        );
+        assert_eq!(
+            auth.authorize("DELETE", &authorization(nonce, &ha1), &file),
+            None
+        );
+        let wrong = hex(&md5(b"mufasa:zoo:tiger"));
+        assert_eq!(
+            auth.authorize("PUT", &authorization(nonce, &wrong), &file),
+            None
+        );
+        assert_eq!(
+            auth.authorize("PUT", &authorization(nonce + 1, &ha1), &file),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_params() {
+        let parsed = params(r#"username="Mufasa", qop=auth, nc=00000001,uri="/a\"b""#);
+        let parsed: Vec<_> = parsed
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("username", "Mufasa"),
+                ("qop", "auth"),
+                ("nc", "00000001"),
+                ("uri", "/a\"b"),
+            ]
+        );
+    }
+}
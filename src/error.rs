@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::fmt;
+
+use conduit::{header, Body, RequestExt, Response, StatusCode};
+
+/// Renders the response sent when a request fails.
+///
+/// The hook is called with the error returned by the handler, or with a
+/// `NoResponse` error if a request finished without any response being
+/// written. It is a good place to log errors, and to pick a representation
+/// such as a JSON problem document in production or a detailed page during
+/// development.
+///
+/// Closures with a matching signature implement this trait.
+///
+/// ```
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use civet::Config;
+/// use conduit::{Body, RequestExt, Response, StatusCode};
+///
+/// let mut config = Config::new();
+/// config.error_handler(|err: &(dyn std::error::Error + Send + 'static), req: &dyn RequestExt| {
+///     eprintln!("{} {} failed: {}", req.method(), req.path(), err);
+///     let mut res = Response::new(Body::from_static(b"Something went wrong"));
+///     *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+///     res
+/// });
+/// ```
+pub trait ErrorHandler: Sync + Send + 'static {
+    fn handle_error(
+        &self,
+        error: &(dyn Error + Send + 'static),
+        request: &dyn RequestExt,
+    ) -> Response<Body>;
+
+    /// Renders an error that civetweb generates itself, before a request
+    /// reaches the handler, such as a 400 for an invalid URI or HTTP version.
+    /// civetweb 1.6 answers requests it cannot parse at all, including ones
+    /// with oversized headers, with a 500.
+    ///
+    /// Returning `None` leaves the page to civetweb, which uses the templates
+    /// from `Config::error_pages` if they are configured.
+    fn http_error(&self, _status: StatusCode) -> Option<Response<Body>> {
+        None
+    }
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(&(dyn Error + Send + 'static), &dyn RequestExt) -> Response<Body> + Sync + Send + 'static,
+{
+    fn handle_error(
+        &self,
+        error: &(dyn Error + Send + 'static),
+        request: &dyn RequestExt,
+    ) -> Response<Body> {
+        (*self)(error, request)
+    }
+}
+
+/// The default `ErrorHandler`, which sends an empty 500 response.
+pub struct DefaultErrorHandler;
+
+impl ErrorHandler for DefaultErrorHandler {
+    fn handle_error(
+        &self,
+        _error: &(dyn Error + Send + 'static),
+        _request: &dyn RequestExt,
+    ) -> Response<Body> {
+        internal_server_error()
+    }
+}
+
+pub(crate) fn internal_server_error() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response.headers_mut().insert(
+        header::CONTENT_LENGTH,
+        header::HeaderValue::from_static("0"),
+    );
+    response
+}
+
+/// A plain text page for an error civetweb would otherwise render.
+pub(crate) fn status_page(status: StatusCode) -> Response<Body> {
+    let body = format!(
+        "Error {}: {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Unknown")
+    );
+    let mut response = Response::new(Body::from_vec(body.into_bytes()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+/// The error passed to an `ErrorHandler` when a request completed without
+/// writing a response.
+#[derive(Debug)]
+pub struct NoResponse;
+
+impl fmt::Display for NoResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the request finished without sending a response")
+    }
+}
+
+impl Error for NoResponse {}
@@ -0,0 +1,691 @@
+//! Streaming parsers for `multipart/form-data` and
+//! `application/x-www-form-urlencoded` request bodies.
+
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use conduit::header::{self, HeaderMap, HeaderName, HeaderValue};
+use conduit::RequestExt;
+use tempfile::NamedTempFile;
+
+/// Longest header block accepted for a single multipart part.
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+/// Limits applied while parsing a form.
+pub struct FormLimits {
+    max_parts: usize,
+    max_part_size: u64,
+    spill_dir: Option<PathBuf>,
+}
+
+impl Default for FormLimits {
+    fn default() -> FormLimits {
+        FormLimits {
+            max_parts: 128,
+            max_part_size: 16 * 1024 * 1024,
+            spill_dir: None,
+        }
+    }
+}
+
+impl FormLimits {
+    pub fn new() -> FormLimits {
+        FormLimits::default()
+    }
+
+    /// The maximum number of parts or fields in a form (default 128)
+    pub fn max_parts(&mut self, max_parts: usize) -> &mut FormLimits {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// The maximum size in bytes of a single part or field (default 16 MiB)
+    pub fn max_part_size(&mut self, max_part_size: u64) -> &mut FormLimits {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    /// The directory used by `Part::spill`, instead of the system temp dir
+    pub fn spill_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut FormLimits {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+}
+
+/// A form body, read one part at a time.
+///
+/// ```no_run
+/// # extern crate civet;
+/// # extern crate conduit;
+/// # use std::io::Read;
+/// # fn handler(req: &mut dyn conduit::RequestExt) -> std::io::Result<()> {
+/// use civet::{Form, FormLimits};
+///
+/// let limits = FormLimits::new();
+/// let mut form = Form::new(req, &limits)?;
+/// while let Some(mut part) = form.next_part()? {
+///     let mut value = Vec::new();
+///     part.read_to_end(&mut value)?;
+/// }
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+pub struct Form<'r> {
+    kind: Kind<'r>,
+    limits: &'r FormLimits,
+    parts: usize,
+}
+
+enum Kind<'r> {
+    Multipart(Multipart<'r>),
+    UrlEncoded(UrlEncoded<'r>),
+}
+
+impl<'r> Form<'r> {
+    /// Starts parsing the body of `req` according to its `Content-Type`.
+    ///
+    /// Fails with `InvalidInput` if the request does not carry a form.
+    pub fn new(req: &'r mut dyn RequestExt, limits: &'r FormLimits) -> io::Result<Form<'r>> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Form::from_reader(&content_type, req.body(), limits)
+    }
+
+    /// Starts parsing `body` as a form of the given `Content-Type`.
+    pub fn from_reader(
+        content_type: &str,
+        body: &'r mut dyn Read,
+        limits: &'r FormLimits,
+    ) -> io::Result<Form<'r>> {
+        let mut params = content_type.split(';');
+        let mime = params.next().unwrap_or_default().trim();
+
+        let kind = if mime.eq_ignore_ascii_case("multipart/form-data") {
+            let boundary = params
+                .filter_map(|param| {
+                    let (name, value) = split_once(param, '=')?;
+                    if name.trim().eq_ignore_ascii_case("boundary") {
+                        Some(unquote(value.trim()).to_string())
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "missing multipart boundary")
+                })?;
+            Kind::Multipart(Multipart::new(body, &boundary, limits.max_part_size))
+        } else if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            Kind::UrlEncoded(UrlEncoded {
+                body: BufReader::new(body),
+                done: false,
+            })
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request body is not a form",
+            ));
+        };
+
+        Ok(Form {
+            kind,
+            limits,
+            parts: 0,
+        })
+    }
+
+    /// Returns the next part of the form, skipping any unread data of the
+    /// previous part.
+    pub fn next_part(&mut self) -> io::Result<Option<Part<'_>>> {
+        let part = match self.kind {
+            Kind::Multipart(ref mut multipart) => match multipart.next_headers()? {
+                None => return Ok(None),
+                Some(headers) => {
+                    let (name, filename) = headers
+                        .get(header::CONTENT_DISPOSITION)
+                        .and_then(|value| value.to_str().ok())
+                        .map(parse_disposition)
+                        .unwrap_or_default();
+                    Part {
+                        name: name.unwrap_or_default(),
+                        filename: filename.as_ref().and_then(|f| sanitize_filename(f)),
+                        headers,
+                        body: PartBody::Multipart(multipart),
+                        spill_dir: self.limits.spill_dir.as_deref(),
+                    }
+                }
+            },
+            Kind::UrlEncoded(ref mut urlencoded) => {
+                match urlencoded.next_field(self.limits.max_part_size)? {
+                    None => return Ok(None),
+                    Some((name, value)) => Part {
+                        name,
+                        filename: None,
+                        headers: HeaderMap::new(),
+                        body: PartBody::Buffered(Cursor::new(value)),
+                        spill_dir: self.limits.spill_dir.as_deref(),
+                    },
+                }
+            }
+        };
+
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(invalid("too many form parts"));
+        }
+        Ok(Some(part))
+    }
+}
+
+/// A single part of a form.
+///
+/// The part's content is streamed from the request body through `Read`.
+pub struct Part<'f> {
+    name: String,
+    filename: Option<String>,
+    headers: HeaderMap,
+    body: PartBody<'f>,
+    spill_dir: Option<&'f Path>,
+}
+
+enum PartBody<'f> {
+    Multipart(&'f mut (dyn Read + 'f)),
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl<'f> Part<'f> {
+    /// The field name from the part's `Content-Disposition`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The client supplied file name, stripped of any path components and
+    /// control characters
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The `Content-Type` of the part, if one was sent
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// All headers sent with the part
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Copies the remainder of the part into a temporary file.
+    ///
+    /// The file is created in `FormLimits::spill_dir`, or the system temp
+    /// directory, and is deleted when the returned handle is dropped unless it
+    /// is persisted. The file is rewound before being returned.
+    pub fn spill(mut self) -> io::Result<NamedTempFile> {
+        let mut file = match self.spill_dir {
+            Some(dir) => NamedTempFile::new_in(dir)?,
+            None => NamedTempFile::new()?,
+        };
+        io::copy(&mut self, &mut file)?;
+        file.as_file_mut().seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+}
+
+impl<'f> Read for Part<'f> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.body {
+            PartBody::Multipart(ref mut body) => body.read(buf),
+            PartBody::Buffered(ref mut body) => body.read(buf),
+        }
+    }
+}
+
+struct Multipart<'r> {
+    body: &'r mut dyn Read,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+    state: MultipartState,
+    max_part_size: u64,
+    part_size: u64,
+}
+
+#[derive(PartialEq)]
+enum MultipartState {
+    Preamble,
+    Part,
+    PartEnd,
+    Done,
+}
+
+impl<'r> Multipart<'r> {
+    fn new(body: &'r mut dyn Read, boundary: &str, max_part_size: u64) -> Multipart<'r> {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Multipart {
+            body,
+            delimiter,
+            // The leading CRLF lets the first boundary match the delimiter
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: MultipartState::Preamble,
+            max_part_size,
+            part_size: 0,
+        }
+    }
+
+    /// Reads more of the body into the buffer, returning false at EOF.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; 8 * 1024];
+        let n = self.body.read(&mut chunk)?;
+        if n == 0 {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Ensures at least `n` bytes are buffered, unless the body ends first.
+    fn fill_to(&mut self, n: usize) -> io::Result<()> {
+        while self.buf.len() < n && self.fill()? {}
+        Ok(())
+    }
+
+    /// Advances past the next delimiter and parses the following headers.
+    fn next_headers(&mut self) -> io::Result<Option<HeaderMap>> {
+        loop {
+            match self.state {
+                MultipartState::Done => return Ok(None),
+                MultipartState::Preamble | MultipartState::Part => {
+                    // Discard data up to the next delimiter
+                    let mut sink = [0; 8 * 1024];
+                    while self.read_part(&mut sink)? > 0 {}
+                    self.state = MultipartState::PartEnd;
+                }
+                MultipartState::PartEnd => {
+                    let skip = self.delimiter.len();
+                    self.buf.drain(..skip);
+                    self.fill_to(2)?;
+                    if self.buf.starts_with(b"--") {
+                        self.state = MultipartState::Done;
+                        return Ok(None);
+                    }
+                    let headers = self.read_headers()?;
+                    self.state = MultipartState::Part;
+                    self.part_size = 0;
+                    return Ok(Some(headers));
+                }
+            }
+        }
+    }
+
+    fn read_headers(&mut self) -> io::Result<HeaderMap> {
+        // The rest of the boundary line, which may contain transport padding
+        let end = loop {
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                break pos;
+            }
+            if self.buf.len() > MAX_PART_HEADERS {
+                return Err(invalid("multipart headers too long"));
+            }
+            if !self.fill()? {
+                return Err(eof());
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        let block = self.buf.drain(..end + 4).collect::<Vec<_>>();
+        let mut lines = block.split(|&b| b == b'\n').map(|line| match line.last() {
+            Some(b'\r') => &line[..line.len() - 1],
+            _ => line,
+        });
+        // Skip the remainder of the boundary line
+        lines.next();
+        for line in lines.filter(|line| !line.is_empty()) {
+            let colon = line
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| invalid("invalid multipart header"))?;
+            let name = HeaderName::from_bytes(&line[..colon])
+                .map_err(|_| invalid("invalid multipart header name"))?;
+            let value = String::from_utf8_lossy(&line[colon + 1..]);
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|_| invalid("invalid multipart header value"))?;
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// Reads part data, returning 0 once the next delimiter is reached.
+    fn read_part(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let delimiter = self.delimiter.len();
+        loop {
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(pos) => pos,
+                // A partial delimiter may be at the end of the buffer
+                None => self.buf.len().saturating_sub(delimiter - 1),
+            };
+            if available > 0 {
+                let n = cmp::min(available, out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                if self.state == MultipartState::Part {
+                    self.part_size += n as u64;
+                    if self.part_size > self.max_part_size {
+                        return Err(invalid("form part too large"));
+                    }
+                }
+                return Ok(n);
+            }
+            if self.buf.starts_with(&self.delimiter) {
+                return Ok(0);
+            }
+            if !self.fill()? {
+                return Err(eof());
+            }
+        }
+    }
+}
+
+impl<'r> Read for Multipart<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state != MultipartState::Part {
+            return Ok(0);
+        }
+        let n = self.read_part(buf)?;
+        if n == 0 {
+            self.state = MultipartState::PartEnd;
+        }
+        Ok(n)
+    }
+}
+
+struct UrlEncoded<'r> {
+    body: BufReader<&'r mut dyn Read>,
+    done: bool,
+}
+
+impl<'r> UrlEncoded<'r> {
+    fn next_field(&mut self, max_size: u64) -> io::Result<Option<(String, Vec<u8>)>> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            let mut field = Vec::new();
+            (&mut self.body)
+                .take(max_size.saturating_add(1))
+                .read_until(b'&', &mut field)?;
+            if field.last() == Some(&b'&') {
+                field.pop();
+            } else if field.len() as u64 > max_size {
+                return Err(invalid("form field too large"));
+            } else {
+                self.done = true;
+            }
+            if field.is_empty() {
+                continue;
+            }
+            let (name, value) = match field.iter().position(|&b| b == b'=') {
+                Some(pos) => (&field[..pos], &field[pos + 1..]),
+                None => (&field[..], &b""[..]),
+            };
+            let name = String::from_utf8_lossy(&percent_decode(name)).into_owned();
+            return Ok(Some((name, percent_decode(value))));
+        }
+    }
+}
+
+fn percent_decode(input: &[u8]) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < input.len() => match (hex(input[i + 1]), hex(input[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Extracts the `name` and `filename` parameters of a `Content-Disposition`.
+fn parse_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    for param in split_params(value).into_iter().skip(1) {
+        if let Some((key, value)) = split_once(&param, '=') {
+            let value = unquote(value.trim()).replace("\\\"", "\"");
+            match key.trim().to_ascii_lowercase().as_str() {
+                "name" => name = Some(value),
+                "filename" => filename = Some(value),
+                _ => {}
+            }
+        }
+    }
+    (name, filename)
+}
+
+/// Splits on `;` outside of quoted strings.
+fn split_params(value: &str) -> Vec<String> {
+    let mut params = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            ';' if !quoted => {
+                params.push(String::new());
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            _ => {}
+        }
+        escaped = c == '\\' && !escaped;
+        params.last_mut().unwrap().push(c);
+    }
+    params
+}
+
+/// Strips directories, control characters and leading dots from a client
+/// supplied file name, returning `None` if nothing usable remains.
+fn sanitize_filename(filename: &str) -> Option<String> {
+    let base = filename.rsplit(['/', '\\']).next()?;
+    let clean = base.chars().filter(|c| !c.is_control()).collect::<String>();
+    let clean = clean.trim().trim_start_matches('.').trim();
+    if clean.is_empty() {
+        None
+    } else {
+        Some(clean.chars().take(255).collect())
+    }
+}
+
+fn split_once(s: &str, delim: char) -> Option<(&str, &str)> {
+    let pos = s.find(delim)?;
+    Some((&s[..pos], &s[pos + delim.len_utf8()..]))
+}
+
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of form body")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sanitize_filename, Form, FormLimits};
+    use std::io::{self, Read};
+
+    fn parts(
+        content_type: &str,
+        body: &str,
+        limits: &FormLimits,
+    ) -> io::Result<Vec<(String, Option<String>, String)>> {
+        let mut body = body.as_bytes();
+        let mut form = Form::from_reader(content_type, &mut body, limits)?;
+        let mut parts = Vec::new();
+        while let Some(mut part) = form.next_part()? {
+            let mut value = String::new();
+            part.read_to_string(&mut value)?;
+            parts.push((
+                part.name().to_string(),
+                part.filename().map(String::from),
+                value,
+            ));
+        }
+        Ok(parts)
+    }
+
+    const MULTIPART: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"../../etc/pa;sswd\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n--XyW not yet\r\n\
+        --XyZ--\r\n";
+
+    #[test]
+    fn multipart() {
+        let parts = parts(
+            "multipart/form-data; boundary=\"XyZ\"",
+            MULTIPART,
+            &FormLimits::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                ("title".to_string(), None, "hello".to_string()),
+                (
+                    "file".to_string(),
+                    Some("pa;sswd".to_string()),
+                    "line one\r\n--XyW not yet".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unread_parts() {
+        let limits = FormLimits::new();
+        let mut body = MULTIPART.as_bytes();
+        let mut form =
+            Form::from_reader("multipart/form-data; boundary=XyZ", &mut body, &limits).unwrap();
+        assert_eq!(form.next_part().unwrap().unwrap().name(), "title");
+        let part = form.next_part().unwrap().unwrap();
+        assert_eq!(part.content_type(), Some("text/plain"));
+        let spilled = part.spill().unwrap();
+        let mut contents = String::new();
+        spilled.as_file().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "line one\r\n--XyW not yet");
+        assert!(form.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let content_type = "multipart/form-data; boundary=XyZ";
+        let err = parts(content_type, MULTIPART, FormLimits::new().max_parts(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = parts(content_type, MULTIPART, FormLimits::new().max_part_size(8)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = parts(content_type, "--XyZ\r\n\r\ntruncated", &FormLimits::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let urlencoded = "application/x-www-form-urlencoded";
+        let mut limits = FormLimits::new();
+        limits.max_part_size(3);
+        assert!(parts(urlencoded, "a=1&b=2", &limits).is_ok());
+        let err = parts(urlencoded, "a=1&b=22", &limits).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn urlencoded() {
+        let parts = parts(
+            "application/x-www-form-urlencoded",
+            "a=1&b=hello+world%21&&flag&c=%zz",
+            &FormLimits::new(),
+        )
+        .unwrap();
+        let parts = parts
+            .into_iter()
+            .map(|(name, _, value)| (name, value))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("a", "1"),
+            ("b", "hello world!"),
+            ("flag", ""),
+            ("c", "%zz"),
+        ];
+        assert_eq!(
+            parts,
+            expected
+                .iter()
+                .map(|&(n, v)| (n.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_non_forms() {
+        let err = parts("text/plain", "", &FormLimits::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = parts("multipart/form-data", "", &FormLimits::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\cv.pdf").unwrap(),
+            "cv.pdf"
+        );
+        assert_eq!(sanitize_filename("..\u{0}.bashrc").unwrap(), "bashrc");
+        assert_eq!(sanitize_filename("dir/"), None);
+        assert_eq!(sanitize_filename(".."), None);
+    }
+}
@@ -0,0 +1,249 @@
+use std::net::{IpAddr, SocketAddr};
+
+use conduit::{HeaderMap, Scheme};
+
+use cidr::Cidr;
+
+/// What a chain of trusted proxies reported about the original request.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Forwarded {
+    pub(crate) addr: Option<SocketAddr>,
+    pub(crate) scheme: Option<Scheme>,
+    pub(crate) host: Option<String>,
+}
+
+/// One proxy's report, from a `Forwarded` element or the matching entries
+/// of the `X-Forwarded-*` headers.
+#[derive(Default)]
+struct Hop {
+    addr: Option<SocketAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Reads the forwarding headers of a request received from `peer`.
+///
+/// Returns `None` unless `peer` is trusted. The client is the rightmost
+/// address in the chain that is not itself a trusted proxy, so entries
+/// prepended by the client cannot take effect. RFC 7239 `Forwarded` headers
+/// are used in preference to `X-Forwarded-*`.
+pub(crate) fn resolve(
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    trusted: &[Cidr],
+) -> Option<Forwarded> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer.ip()) {
+        return None;
+    }
+
+    let hops = if headers.contains_key("forwarded") {
+        forwarded_hops(headers)
+    } else {
+        x_forwarded_hops(headers)
+    };
+
+    let mut client = None;
+    for hop in hops.iter().rev() {
+        client = Some(hop);
+        match hop.addr {
+            Some(addr) if is_trusted(addr.ip()) => continue,
+            _ => break,
+        }
+    }
+    let hop = client?;
+    Some(Forwarded {
+        addr: hop.addr,
+        scheme: hop.proto.as_ref().and_then(|proto| {
+            if proto.eq_ignore_ascii_case("https") {
+                Some(Scheme::Https)
+            } else if proto.eq_ignore_ascii_case("http") {
+                Some(Scheme::Http)
+            } else {
+                None
+            }
+        }),
+        host: hop.host.clone(),
+    })
+}
+
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let mut hops = Vec::new();
+    for value in values(headers, "forwarded") {
+        for element in split_unquoted(value, ',') {
+            let mut hop = Hop::default();
+            for pair in split_unquoted(element, ';') {
+                let mut parts = pair.splitn(2, '=');
+                let name = parts.next().unwrap_or_default().trim();
+                let value = unquote(parts.next().unwrap_or_default().trim());
+                if name.eq_ignore_ascii_case("for") {
+                    hop.addr = parse_node(value);
+                } else if name.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(value.to_string());
+                } else if name.eq_ignore_ascii_case("host") {
+                    hop.host = Some(value.to_string());
+                }
+            }
+            hops.push(hop);
+        }
+    }
+    hops
+}
+
+fn x_forwarded_hops(headers: &HeaderMap) -> Vec<Hop> {
+    let list = |name: &str| {
+        values(headers, name)
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+    let addrs = list("x-forwarded-for");
+    let protos = list("x-forwarded-proto");
+    let hosts = list("x-forwarded-host");
+    let ports = list("x-forwarded-port");
+
+    // The other headers describe the hops of X-Forwarded-For when they have
+    // an entry for each, and otherwise the last one reported the request
+    let pick = |values: &[String], i: usize| {
+        if values.len() == addrs.len() {
+            values.get(i).cloned()
+        } else {
+            values.last().cloned()
+        }
+    };
+    let hop = |i: usize| {
+        let host = pick(&hosts, i).map(|host| match pick(&ports, i) {
+            Some(ref port) if !has_port(&host) && !port.is_empty() => format!("{}:{}", host, port),
+            _ => host,
+        });
+        Hop {
+            addr: addrs.get(i).and_then(|addr| parse_node(addr)),
+            proto: pick(&protos, i),
+            host,
+        }
+    };
+    if addrs.is_empty() {
+        // Proto or host without any address still describe the request
+        vec![hop(0)]
+    } else {
+        (0..addrs.len()).map(hop).collect()
+    }
+}
+
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+}
+
+/// Parses an address such as `192.0.2.1`, `192.0.2.1:4711` or
+/// `[2001:db8::1]:4711`. A missing port is reported as 0.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+fn has_port(host: &str) -> bool {
+    match host.rfind(':') {
+        Some(i) => !host[i..].contains(']'),
+        None => false,
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// Splits `s` on `sep`, ignoring separators inside quoted strings.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(s[start..i].trim());
+            start = i + 1;
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, Forwarded};
+    use cidr::Cidr;
+    use conduit::{HeaderMap, Scheme};
+
+    fn forwarded(peer: &str, headers: &[(&str, &str)]) -> Option<Forwarded> {
+        let trusted = ["10.0.0.0/8".parse::<Cidr>().unwrap()];
+        let mut map = HeaderMap::new();
+        for &(name, value) in headers {
+            map.append(
+                conduit::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        resolve(peer.parse().unwrap(), &map, &trusted)
+    }
+
+    #[test]
+    fn untrusted_peers_are_ignored() {
+        let headers = [("x-forwarded-for", "198.51.100.7")];
+        assert_eq!(forwarded("203.0.113.1:5000", &headers), None);
+    }
+
+    #[test]
+    fn x_forwarded_headers() {
+        let f = forwarded(
+            "10.0.0.2:5000",
+            &[
+                ("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.3"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "example.com"),
+                ("x-forwarded-port", "8443"),
+            ],
+        )
+        .unwrap();
+        // 1.2.3.4 was sent by the client and cannot be trusted
+        assert_eq!(f.addr, Some("198.51.100.7:0".parse().unwrap()));
+        assert_eq!(f.scheme, Some(Scheme::Https));
+        assert_eq!(f.host.as_deref(), Some("example.com:8443"));
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let f = forwarded(
+            "10.0.0.2:5000",
+            &[
+                ("x-forwarded-for", "1.2.3.4"),
+                (
+                    "forwarded",
+                    "for=1.2.3.4;proto=http, for=\"[2001:db8::1]:4711\";proto=https;host=\"a.example\"",
+                ),
+                ("forwarded", "for=10.0.0.9"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(f.addr, Some("[2001:db8::1]:4711".parse().unwrap()));
+        assert_eq!(f.scheme, Some(Scheme::Https));
+        assert_eq!(f.host.as_deref(), Some("a.example"));
+    }
+
+    #[test]
+    fn obfuscated_client() {
+        let f = forwarded("10.0.0.2:5000", &[("forwarded", "for=_hidden;proto=https")]).unwrap();
+        assert_eq!(f.addr, None);
+        assert_eq!(f.scheme, Some(Scheme::Https));
+    }
+}
@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use conduit::{Extensions, StatusCode};
+
+use access::Permit;
+
+/// Observes connections and requests as civetweb processes them.
+///
+/// Every method has an empty default, so implementations only override the
+/// events they care about. Hooks run on the civetweb worker thread that owns
+/// the connection, and a panic inside a hook is caught and ignored.
+///
+/// `connection_open` fires when the first request on a connection begins,
+/// and `connection_close` fires only for connections that were opened, so
+/// the two always pair up. A connection that civetweb rejects before any
+/// request is parsed produces no events.
+///
+/// ```
+/// # extern crate civet;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use civet::{Config, ConnectionInfo, ServerHooks};
+///
+/// struct OpenConnections(AtomicUsize);
+///
+/// impl ServerHooks for OpenConnections {
+///     fn connection_open(&self, _conn: &ConnectionInfo) {
+///         self.0.fetch_add(1, Ordering::SeqCst);
+///     }
+///
+///     fn connection_close(&self, _conn: &ConnectionInfo) {
+///         self.0.fetch_sub(1, Ordering::SeqCst);
+///     }
+/// }
+///
+/// let mut config = Config::new();
+/// config.hooks(OpenConnections(AtomicUsize::new(0)));
+/// ```
+pub trait ServerHooks: Sync + Send + 'static {
+    fn connection_open(&self, _conn: &ConnectionInfo) {}
+
+    fn connection_close(&self, _conn: &ConnectionInfo) {}
+
+    /// Called once a request has been parsed, before it is dispatched.
+    fn begin_request(&self, _conn: &ConnectionInfo) {}
+
+    /// Called after the response has been sent.
+    ///
+    /// `status` is the status of the response written by this crate, or else
+    /// the one civetweb recorded. It is `None` if neither is known.
+    fn end_request(&self, _conn: &ConnectionInfo, _status: Option<StatusCode>) {}
+}
+
+/// Describes the connection a hook is called for.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    id: u64,
+    remote_addr: SocketAddr,
+    opened: Instant,
+    requests: u64,
+    pub(crate) request_id: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// A number identifying the connection, unique within the process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// When the first request on the connection began.
+    pub fn opened(&self) -> Instant {
+        self.opened
+    }
+
+    /// The number of requests begun on the connection so far, including the
+    /// current one. Anything above one means the connection was kept alive.
+    pub fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    /// The `RequestId` of the current request, once it has reached the
+    /// handler. Useful for access logs written from `end_request`.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+}
+
+/// Values kept for the life of a connection, across keep-alive requests.
+///
+/// Inserted into the request's extensions before the handler is called and
+/// taken back once it returns, so anything a handler stores here is visible
+/// to later requests on the same socket. The map is dropped when the
+/// connection closes.
+///
+/// ```
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use civet::ConnectionExtensions;
+/// use conduit::RequestExt;
+///
+/// struct RequestCount(u64);
+///
+/// fn count(req: &mut dyn RequestExt) -> u64 {
+///     let conn = req.mut_extensions().find_mut::<ConnectionExtensions>();
+///     let conn = match conn {
+///         Some(conn) => &mut conn.0,
+///         None => return 1,
+///     };
+///     if !conn.contains::<RequestCount>() {
+///         conn.insert(RequestCount(0));
+///     }
+///     let count = conn.find_mut::<RequestCount>().unwrap();
+///     count.0 += 1;
+///     count.0
+/// }
+/// # fn main() {}
+/// ```
+pub struct ConnectionExtensions(pub Extensions);
+
+/// The state kept in civetweb's `conn_data` for the life of a connection.
+pub(crate) struct ConnState {
+    pub(crate) info: ConnectionInfo,
+    /// The status of the response written for the current request.
+    pub(crate) status: Option<u16>,
+    /// Whether `begin_request` ran for the current request.
+    pub(crate) begun: bool,
+    pub(crate) extensions: Extensions,
+    /// Places held under `AccessControl` connection limits until the
+    /// connection closes.
+    pub(crate) permits: Vec<Permit>,
+}
+
+impl ConnState {
+    pub(crate) fn new(remote_addr: SocketAddr) -> ConnState {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        ConnState {
+            info: ConnectionInfo {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                remote_addr,
+                opened: Instant::now(),
+                requests: 0,
+                request_id: None,
+            },
+            status: None,
+            begun: false,
+            extensions: Extensions::new(),
+            permits: Vec::new(),
+        }
+    }
+
+    pub(crate) fn begin_request(&mut self) {
+        self.info.requests += 1;
+        self.info.request_id = None;
+        self.status = None;
+        self.begun = true;
+    }
+}
@@ -0,0 +1,284 @@
+use std::io;
+
+use conduit::{Body, Handler, HandlerResult, RequestExt, Response};
+
+use config::Config;
+use Server;
+
+/// Code that runs around the handler, added with `ServerBuilder::layer`.
+///
+/// `before` runs in the order the layers were added and `after` in the
+/// reverse order, like the layers of an onion. If a `before` returns a
+/// response the handler and any inner layers are skipped, and only the
+/// layers whose `before` ran see it in `after`. Errors returned by the
+/// handler skip `after` and go to the `ErrorHandler`.
+///
+/// Layers get the request as a `RequestExt`. The server adds the
+/// `ConnectionInfo` of the connection to its extensions, next to the
+/// `RemoteUser`, `RequestId` and any `ProxyTls`.
+///
+/// ```
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use civet::Layer;
+/// use conduit::{header, Body, RequestExt, Response, StatusCode};
+///
+/// struct RequireJson;
+///
+/// impl Layer for RequireJson {
+///     fn before(&self, req: &mut dyn RequestExt) -> Option<Response<Body>> {
+///         if req.headers().get(header::ACCEPT).map_or(true, |v| v == "application/json") {
+///             return None;
+///         }
+///         let mut response = Response::new(Body::empty());
+///         *response.status_mut() = StatusCode::NOT_ACCEPTABLE;
+///         Some(response)
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub trait Layer: Sync + Send + 'static {
+    /// Returns a response to answer the request without calling the
+    /// handler.
+    fn before(&self, _req: &mut dyn RequestExt) -> Option<Response<Body>> {
+        None
+    }
+
+    fn after(&self, _req: &mut dyn RequestExt, _response: &mut Response<Body>) {}
+}
+
+/// Builds a `Server` whose handler is wrapped in layers.
+///
+/// ```no_run
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use civet::{Config, Layer, Server};
+/// use conduit::{Body, RequestExt, Response};
+///
+/// struct PoweredBy;
+///
+/// impl Layer for PoweredBy {
+///     fn after(&self, _req: &mut dyn RequestExt, response: &mut Response<Body>) {
+///         response.headers_mut().insert("x-powered-by", "civet".parse().unwrap());
+///     }
+/// }
+///
+/// fn hello(_req: &mut dyn RequestExt) -> conduit::HttpResult {
+///     Response::builder().body(Body::from_static(b"Hello"))
+/// }
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mut builder = Server::builder();
+/// builder.layer(PoweredBy);
+/// let _server = builder.start(Config::new(), hello)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ServerBuilder {
+    layers: Vec<Box<dyn Layer>>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Adds a layer inside the ones added before it.
+    pub fn layer<L: Layer>(&mut self, layer: L) -> &mut ServerBuilder {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Wraps `handler` in the layers, without starting a server. Useful for
+    /// testing layers against a mock request.
+    pub fn wrap<H: Handler>(self, handler: H) -> Layered {
+        Layered {
+            layers: self.layers,
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn start<H: Handler>(self, config: Config, handler: H) -> io::Result<Server> {
+        Server::start(config, self.wrap(handler))
+    }
+}
+
+/// A handler wrapped in layers, made by `ServerBuilder::wrap`.
+pub struct Layered {
+    layers: Vec<Box<dyn Layer>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Handler for Layered {
+    fn call(&self, req: &mut dyn RequestExt) -> HandlerResult {
+        let mut entered = 0;
+        let mut response = None;
+        for layer in &self.layers {
+            entered += 1;
+            response = layer.before(req);
+            if response.is_some() {
+                break;
+            }
+        }
+        let mut response = match response {
+            Some(response) => response,
+            None => self.handler.call(req)?,
+        };
+        for layer in self.layers[..entered].iter().rev() {
+            layer.after(req, &mut response);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::{Cursor, Read};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use conduit::{
+        Body, Extensions, Handler, HeaderMap, Host, HttpResult, Method, RequestExt, Response,
+        Scheme, Version,
+    };
+
+    use super::{Layer, ServerBuilder};
+
+    /// A request that never touches a socket.
+    pub(crate) struct MockRequest {
+        pub(crate) method: Method,
+        pub(crate) path: String,
+        pub(crate) headers: HeaderMap,
+        pub(crate) body: Cursor<Vec<u8>>,
+        pub(crate) extensions: Extensions,
+    }
+
+    impl MockRequest {
+        pub(crate) fn new(method: Method, path: &str) -> MockRequest {
+            MockRequest {
+                method,
+                path: path.to_string(),
+                headers: HeaderMap::new(),
+                body: Cursor::new(Vec::new()),
+                extensions: Extensions::new(),
+            }
+        }
+    }
+
+    impl RequestExt for MockRequest {
+        fn http_version(&self) -> Version {
+            Version::HTTP_11
+        }
+        fn method(&self) -> &Method {
+            &self.method
+        }
+        fn scheme(&self) -> Scheme {
+            Scheme::Http
+        }
+        fn host(&self) -> Host<'_> {
+            Host::Name("example.com")
+        }
+        fn virtual_root(&self) -> Option<&str> {
+            None
+        }
+        fn path(&self) -> &str {
+            &self.path
+        }
+        fn path_mut(&mut self) -> &mut String {
+            &mut self.path
+        }
+        fn query_string(&self) -> Option<&str> {
+            None
+        }
+        fn remote_addr(&self) -> SocketAddr {
+            SocketAddr::from(([127, 0, 0, 1], 4000))
+        }
+        fn content_length(&self) -> Option<u64> {
+            Some(self.body.get_ref().len() as u64)
+        }
+        fn headers(&self) -> &HeaderMap {
+            &self.headers
+        }
+        fn body(&mut self) -> &mut dyn Read {
+            &mut self.body
+        }
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+        fn mut_extensions(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+    }
+
+    struct Record {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        short_circuit: bool,
+    }
+
+    impl Layer for Record {
+        fn before(&self, _req: &mut dyn RequestExt) -> Option<Response<Body>> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            if self.short_circuit {
+                Some(Response::new(Body::from_static(b"short")))
+            } else {
+                None
+            }
+        }
+
+        fn after(&self, _req: &mut dyn RequestExt, _response: &mut Response<Body>) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+        }
+    }
+
+    fn layered(short_circuit: &'static str) -> (impl Handler, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = ServerBuilder::new();
+        for &name in &["a", "b", "c"] {
+            builder.layer(Record {
+                name,
+                log: log.clone(),
+                short_circuit: name == short_circuit,
+            });
+        }
+        let handler_log = log.clone();
+        let handler = move |_: &mut dyn RequestExt| -> HttpResult {
+            handler_log.lock().unwrap().push("handler".to_string());
+            Response::builder().body(Body::from_static(b"handler"))
+        };
+        (builder.wrap(handler), log)
+    }
+
+    #[test]
+    fn runs_layers_in_order() {
+        let (handler, log) = layered("");
+        let mut req = MockRequest::new(Method::GET, "/");
+        handler.call(&mut req).unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["before a", "before b", "before c", "handler", "after c", "after b", "after a"]
+        );
+    }
+
+    #[test]
+    fn short_circuits() {
+        let (handler, log) = layered("b");
+        let mut req = MockRequest::new(Method::GET, "/");
+        let response = handler.call(&mut req).unwrap();
+        match response.body() {
+            Body::Static(body) => assert_eq!(*body, b"short"),
+            _ => panic!("unexpected body"),
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["before a", "before b", "after b", "after a"]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use conduit::{box_error, header, Body, Handler, HandlerResult, RequestExt, Response};
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Live counters for a server, shared with any `PrometheusExporter`.
+///
+/// Get one from `Config::metrics` before the server starts, or read a
+/// snapshot later with `Server::stats`. Cloning is cheap and every clone
+/// observes the same server.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    responses: [AtomicU64; 5],
+    latency: [AtomicU64; BUCKETS.len() + 1],
+    latency_sum_us: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    connections: AtomicU64,
+    busy_workers: AtomicU64,
+    total_workers: AtomicU64,
+}
+
+impl Metrics {
+    /// A snapshot of the counters.
+    pub fn stats(&self) -> Stats {
+        let c = &self.0;
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        let connections = load(&c.connections);
+        let busy_workers = load(&c.busy_workers);
+        Stats {
+            responses: [
+                load(&c.responses[0]),
+                load(&c.responses[1]),
+                load(&c.responses[2]),
+                load(&c.responses[3]),
+                load(&c.responses[4]),
+            ],
+            latency: Histogram {
+                counts: c.latency.iter().map(load).collect(),
+                sum: Duration::from_micros(load(&c.latency_sum_us)),
+            },
+            bytes_read: load(&c.bytes_read),
+            bytes_written: load(&c.bytes_written),
+            active_connections: connections,
+            idle_connections: connections.saturating_sub(busy_workers),
+            busy_workers,
+            total_workers: load(&c.total_workers),
+        }
+    }
+
+    /// A handler that serves these metrics to Prometheus.
+    pub fn exporter(&self) -> PrometheusExporter {
+        PrometheusExporter(self.clone())
+    }
+
+    pub(crate) fn set_total_workers(&self, workers: u64) {
+        self.0.total_workers.store(workers, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.0.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, bytes: usize) {
+        self.0
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.0.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_started(&self) {
+        self.0.busy_workers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_finished(&self, status: u16) {
+        self.0.busy_workers.fetch_sub(1, Ordering::Relaxed);
+        if let 100..=599 = status {
+            self.0.responses[status as usize / 100 - 1].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn latency(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.0.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.0
+            .latency_sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of a server's metrics.
+///
+/// civetweb does not expose its queue of accepted connections, so there is
+/// no queue length. A connection counts as active from its first request
+/// until it closes, and as idle while it waits for its next request.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    responses: [u64; 5],
+    latency: Histogram,
+    bytes_read: u64,
+    bytes_written: u64,
+    active_connections: u64,
+    idle_connections: u64,
+    busy_workers: u64,
+    total_workers: u64,
+}
+
+impl Stats {
+    /// The number of requests answered so far.
+    pub fn requests(&self) -> u64 {
+        self.responses.iter().sum()
+    }
+
+    /// The number of responses in a status class, such as `5` for 5xx.
+    pub fn responses(&self, class: u8) -> u64 {
+        match class {
+            1..=5 => self.responses[class as usize - 1],
+            _ => 0,
+        }
+    }
+
+    /// How long the handler took to answer, from `StartInstant` until the
+    /// response was written.
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Request body bytes read.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Response bytes written, headers included.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections
+    }
+
+    pub fn idle_connections(&self) -> u64 {
+        self.idle_connections
+    }
+
+    /// Worker threads serving a request.
+    pub fn busy_workers(&self) -> u64 {
+        self.busy_workers
+    }
+
+    pub fn total_workers(&self) -> u64 {
+        self.total_workers
+    }
+
+    /// Renders the snapshot in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = self.write_prometheus(&mut out);
+        out
+    }
+
+    fn write_prometheus(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "# TYPE civet_requests_total counter")?;
+        for class in 1..=5 {
+            writeln!(
+                out,
+                "civet_requests_total{{status=\"{}xx\"}} {}",
+                class,
+                self.responses(class)
+            )?;
+        }
+
+        writeln!(out, "# TYPE civet_request_duration_seconds histogram")?;
+        for (bound, count) in self.latency.buckets() {
+            writeln!(
+                out,
+                "civet_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound.as_secs_f64(),
+                count
+            )?;
+        }
+        writeln!(
+            out,
+            "civet_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency.count()
+        )?;
+        writeln!(
+            out,
+            "civet_request_duration_seconds_sum {}",
+            self.latency.sum().as_secs_f64()
+        )?;
+        writeln!(
+            out,
+            "civet_request_duration_seconds_count {}",
+            self.latency.count()
+        )?;
+
+        let gauges: &[(&str, &str, u64)] = &[
+            ("civet_read_bytes_total", "counter", self.bytes_read),
+            ("civet_written_bytes_total", "counter", self.bytes_written),
+            ("civet_connections_active", "gauge", self.active_connections),
+            ("civet_connections_idle", "gauge", self.idle_connections),
+            ("civet_workers_busy", "gauge", self.busy_workers),
+            ("civet_workers_total", "gauge", self.total_workers),
+        ];
+        for &(name, kind, value) in gauges {
+            writeln!(out, "# TYPE {} {}", name, kind)?;
+            writeln!(out, "{} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Request latencies, grouped into buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    sum: Duration,
+}
+
+impl Histogram {
+    /// Each bucket's upper bound with the number of requests at or below
+    /// it. Slower requests are only included in `count`.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        let mut total = 0;
+        BUCKETS
+            .iter()
+            .zip(&self.counts)
+            .map(|(&bound, &count)| {
+                total += count;
+                (Duration::from_secs_f64(bound), total)
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+/// Serves a server's metrics in the Prometheus text format.
+///
+/// ```
+/// # extern crate civet;
+/// use civet::Config;
+///
+/// let config = Config::new();
+/// let exporter = config.metrics().exporter();
+/// // Route `/metrics` to `exporter`
+/// ```
+#[derive(Clone)]
+pub struct PrometheusExporter(Metrics);
+
+impl Handler for PrometheusExporter {
+    fn call(&self, _req: &mut dyn RequestExt) -> HandlerResult {
+        let body = self.0.stats().to_prometheus().into_bytes();
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from_vec(body))
+            .map_err(box_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn snapshot() {
+        let metrics = Metrics::default();
+        metrics.set_total_workers(4);
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.request_started();
+        metrics.request_finished(200);
+        metrics.request_started();
+        metrics.request_finished(503);
+        metrics.request_started();
+        metrics.latency(Duration::from_millis(3));
+        metrics.latency(Duration::from_millis(300));
+        metrics.latency(Duration::from_secs(60));
+        metrics.written(100);
+
+        let stats = metrics.stats();
+        assert_eq!(stats.requests(), 2);
+        assert_eq!(stats.responses(2), 1);
+        assert_eq!(stats.responses(5), 1);
+        assert_eq!(stats.busy_workers(), 1);
+        assert_eq!(stats.idle_connections(), 1);
+        assert_eq!(stats.total_workers(), 4);
+        assert_eq!(stats.bytes_written(), 100);
+
+        let latency = stats.latency();
+        assert_eq!(latency.count(), 3);
+        assert_eq!(latency.buckets()[0], (Duration::from_millis(5), 1));
+        assert_eq!(latency.buckets().last().unwrap().1, 2);
+
+        let text = stats.to_prometheus();
+        assert!(text.contains("civet_requests_total{status=\"5xx\"} 1\n"));
+        assert!(text.contains("civet_request_duration_seconds_bucket{le=\"0.5\"} 2\n"));
+        assert!(text.contains("civet_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("civet_workers_total 4\n"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use conduit::{header, Body, Method, Response, StatusCode};
+
+/// Receives a report for every panic raised by a request handler.
+///
+/// Closures taking a `&PanicReport` implement this trait.
+pub trait PanicReporter: Sync + Send + 'static {
+    fn report(&self, report: &PanicReport<'_>);
+}
+
+impl<F> PanicReporter for F
+where
+    F: Fn(&PanicReport<'_>) + Sync + Send + 'static,
+{
+    fn report(&self, report: &PanicReport<'_>) {
+        (*self)(report)
+    }
+}
+
+/// Details of a handler panic.
+pub struct PanicReport<'a> {
+    pub(crate) panic: &'a Panic,
+    pub(crate) method: &'a Method,
+    pub(crate) path: &'a str,
+    pub(crate) request_id: Option<&'a str>,
+}
+
+impl<'a> PanicReport<'a> {
+    /// The panic message, if the payload was a string
+    pub fn message(&self) -> Option<&str> {
+        self.panic.message.as_deref()
+    }
+
+    /// The source location of the panic, as `file:line:column`
+    pub fn location(&self) -> Option<&str> {
+        self.panic.location.as_deref()
+    }
+
+    /// The backtrace captured when the handler panicked, if enabled with
+    /// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.panic.backtrace.as_ref()
+    }
+
+    /// The method of the request being handled
+    pub fn method(&self) -> &Method {
+        self.method
+    }
+
+    /// The path of the request being handled
+    pub fn path(&self) -> &str {
+        self.path
+    }
+
+    /// The `RequestId` of the request being handled
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id
+    }
+}
+
+/// A panic caught by `catch`.
+pub struct Panic {
+    message: Option<String>,
+    location: Option<String>,
+    backtrace: Option<Backtrace>,
+}
+
+thread_local! {
+    static CAPTURING: Cell<bool> = const { Cell::new(false) };
+    static CAPTURED: RefCell<Option<(String, Option<Backtrace>)>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, catching any panic along with its location and backtrace.
+pub fn catch<F: FnOnce() -> R, R>(f: F) -> Result<R, Panic> {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CAPTURING.with(Cell::get) {
+                let location = info
+                    .location()
+                    .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+                    .unwrap_or_default();
+                let backtrace = Backtrace::capture();
+                let backtrace = match backtrace.status() {
+                    BacktraceStatus::Captured => Some(backtrace),
+                    _ => None,
+                };
+                let captured = (location, backtrace);
+                CAPTURED.with(|c| *c.borrow_mut() = Some(captured));
+            }
+            previous(info);
+        }));
+    });
+
+    // A panic that an earlier `f` caught itself leaves a capture behind
+    CAPTURED.with(|c| c.borrow_mut().take());
+    CAPTURING.with(|c| c.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CAPTURING.with(|c| c.set(false));
+
+    result.map_err(|payload| {
+        let (location, backtrace) = match CAPTURED.with(|c| c.borrow_mut().take()) {
+            Some((location, backtrace)) => (Some(location), backtrace),
+            None => (None, None),
+        };
+        Panic {
+            message: message(&*payload),
+            location: location.filter(|l| !l.is_empty()),
+            backtrace,
+        }
+    })
+}
+
+fn message(payload: &(dyn Any + Send)) -> Option<String> {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        Some(s.to_string())
+    } else {
+        payload.downcast_ref::<String>().cloned()
+    }
+}
+
+/// The response sent after a handler panics.
+///
+/// The connection is closed, as the request body may not have been read.
+pub fn panic_response() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_LENGTH,
+        header::HeaderValue::from_static("0"),
+    );
+    headers.insert(
+        header::CONNECTION,
+        header::HeaderValue::from_static("close"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::catch;
+    use std::backtrace::{Backtrace, BacktraceStatus};
+
+    #[test]
+    fn captures_panics() {
+        assert_eq!(catch(|| 1).ok(), Some(1));
+
+        let panic = catch(|| panic!("oh {}", "no")).err().unwrap();
+        assert_eq!(panic.message.as_deref(), Some("oh no"));
+        assert!(panic.location.unwrap().contains("panics.rs"));
+        let enabled = Backtrace::capture().status() == BacktraceStatus::Captured;
+        assert_eq!(panic.backtrace.is_some(), enabled);
+
+        // Panics outside of `catch` are not recorded
+        let _ = std::panic::catch_unwind(|| panic!("elsewhere"));
+        let panic = catch(|| std::panic::panic_any(42)).err().unwrap();
+        assert_eq!(panic.message, None);
+        assert!(panic.location.is_some());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{
+    Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream,
+};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client may take to send its PROXY header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most connections a relay serves at once. Further connections are
+/// closed as they are accepted.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// The longest v1 header, including the CRLF.
+const MAX_V1_HEADER: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+/// The version of HAProxy's PROXY protocol a listener requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// The human-readable `PROXY TCP4 ...` line.
+    V1,
+    /// The binary header, which can also carry TLS details.
+    V2,
+}
+
+/// Details of the TLS connection a load balancer terminated, from the TLVs
+/// of a PROXY protocol v2 header.
+///
+/// Inserted into the request's extensions for connections whose header
+/// carried a `PP2_TYPE_SSL` TLV.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyTls {
+    version: Option<String>,
+    cipher: Option<String>,
+    common_name: Option<String>,
+    sni: Option<String>,
+    alpn: Option<Vec<u8>>,
+    client_cert: bool,
+    verified: bool,
+}
+
+impl ProxyTls {
+    /// The TLS version, such as `TLSv1.3`.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn cipher(&self) -> Option<&str> {
+        self.cipher.as_deref()
+    }
+
+    /// The common name of the client certificate's subject.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The server name the client asked for.
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// The negotiated application protocol, such as `h2`.
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    /// Whether the client presented a certificate.
+    pub fn client_cert(&self) -> bool {
+        self.client_cert
+    }
+
+    /// Whether the client certificate was verified by the load balancer.
+    pub fn verified(&self) -> bool {
+        self.client_cert && self.verified
+    }
+}
+
+/// What a relayed connection's PROXY header said about the client.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Proxied {
+    pub(crate) source: SocketAddr,
+    pub(crate) tls: Option<ProxyTls>,
+}
+
+/// The clients of relayed connections, keyed by the address civetweb sees
+/// for the relay.
+pub(crate) type ProxyTable = Arc<Mutex<HashMap<SocketAddr, Proxied>>>;
+
+/// Accepts PROXY protocol connections and relays them to civetweb.
+pub(crate) struct Relay {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Relay {
+    pub(crate) fn start(
+        port: u16,
+        version: ProxyProtocol,
+        target: SocketAddr,
+        table: ProxyTable,
+    ) -> io::Result<Relay> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let active = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn(move || {
+            for client in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => continue,
+                };
+                if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let table = table.clone();
+                let finished = active.clone();
+                let spawned = thread::Builder::new().spawn(move || {
+                    relay(client, version, target, &table);
+                    finished.fetch_sub(1, Ordering::SeqCst);
+                });
+                if spawned.is_err() {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        });
+        Ok(Relay {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so that it sees the flag
+        let wake = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.addr.port());
+        let _ = TcpStream::connect(wake);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn relay(mut client: TcpStream, version: ProxyProtocol, target: SocketAddr, table: &ProxyTable) {
+    let peer = match client.peer_addr() {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+    let _ = client.set_read_timeout(Some(HEADER_TIMEOUT));
+    // A connection without a valid header is dropped, as the spec requires
+    let proxied = match read_header(&mut client, version, peer) {
+        Ok(proxied) => proxied,
+        Err(_) => return,
+    };
+    let _ = client.set_read_timeout(None);
+
+    let upstream = match TcpStream::connect(target) {
+        Ok(upstream) => upstream,
+        Err(_) => return,
+    };
+    let key = match upstream.local_addr() {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    table
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, proxied);
+
+    if let (Ok(mut client_in), Ok(mut upstream_out)) = (client.try_clone(), upstream.try_clone()) {
+        let requests = thread::Builder::new().spawn(move || {
+            let _ = io::copy(&mut client_in, &mut upstream_out);
+            let _ = upstream_out.shutdown(Shutdown::Write);
+        });
+        if let Ok(requests) = requests {
+            let mut upstream = upstream;
+            let _ = io::copy(&mut upstream, &mut client);
+            let _ = client.shutdown(Shutdown::Both);
+            let _ = requests.join();
+        }
+    }
+
+    table.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+}
+
+/// Reads a PROXY header without consuming anything after it.
+///
+/// `peer` is reported as the source of connections whose header carries no
+/// client address, such as health checks from the load balancer itself.
+pub(crate) fn read_header<R: Read>(
+    reader: &mut R,
+    version: ProxyProtocol,
+    peer: SocketAddr,
+) -> io::Result<Proxied> {
+    match version {
+        ProxyProtocol::V1 => read_v1(reader, peer),
+        ProxyProtocol::V2 => read_v2(reader, peer),
+    }
+}
+
+fn read_v1<R: Read>(reader: &mut R, peer: SocketAddr) -> io::Result<Proxied> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_HEADER {
+            return Err(invalid("PROXY header too long"));
+        }
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line =
+        str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("invalid PROXY header"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let source = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => peer,
+        ["PROXY", "TCP4", src, _, sport, _] | ["PROXY", "TCP6", src, _, sport, _] => {
+            let ip = src.parse().map_err(|_| invalid("invalid PROXY source"))?;
+            let port = sport.parse().map_err(|_| invalid("invalid PROXY port"))?;
+            SocketAddr::new(ip, port)
+        }
+        _ => return Err(invalid("invalid PROXY header")),
+    };
+    Ok(Proxied { source, tls: None })
+}
+
+fn read_v2<R: Read>(reader: &mut R, peer: SocketAddr) -> io::Result<Proxied> {
+    let mut head = [0; 16];
+    reader.read_exact(&mut head)?;
+    if &head[..12] != V2_SIGNATURE || head[12] >> 4 != 2 {
+        return Err(invalid("invalid PROXY v2 header"));
+    }
+    let local = match head[12] & 0x0f {
+        0 => true,
+        1 => false,
+        _ => return Err(invalid("unknown PROXY v2 command")),
+    };
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    let (source, tlvs) = match head[13] >> 4 {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            (SocketAddr::V4(SocketAddrV4::new(ip, port)), &body[12..])
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            let addr = SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0);
+            (SocketAddr::V6(addr), &body[36..])
+        }
+        1 | 2 => return Err(invalid("truncated PROXY v2 addresses")),
+        // AF_UNSPEC and AF_UNIX carry no usable address
+        _ => (peer, &[][..]),
+    };
+    if local {
+        return Ok(Proxied {
+            source: peer,
+            tls: None,
+        });
+    }
+
+    let mut tls = None;
+    let mut sni = None;
+    let mut alpn = None;
+    for (kind, value) in tlvs_of(tlvs)? {
+        match kind {
+            PP2_TYPE_ALPN => alpn = Some(value.to_vec()),
+            PP2_TYPE_AUTHORITY => sni = Some(String::from_utf8_lossy(value).into_owned()),
+            PP2_TYPE_SSL if value.len() >= 5 => {
+                let client = value[0];
+                let verify = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let mut info = ProxyTls {
+                    client_cert: client & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) != 0,
+                    verified: verify == 0,
+                    ..ProxyTls::default()
+                };
+                for (kind, value) in tlvs_of(&value[5..])? {
+                    let value = Some(String::from_utf8_lossy(value).into_owned());
+                    match kind {
+                        PP2_SUBTYPE_SSL_VERSION => info.version = value,
+                        PP2_SUBTYPE_SSL_CN => info.common_name = value,
+                        PP2_SUBTYPE_SSL_CIPHER => info.cipher = value,
+                        _ => {}
+                    }
+                }
+                tls = Some(info);
+            }
+            _ => {}
+        }
+    }
+    if let Some(tls) = &mut tls {
+        tls.sni = sni;
+        tls.alpn = alpn;
+    }
+    Ok(Proxied { source, tls })
+}
+
+/// Splits a run of type-length-value records.
+fn tlvs_of(mut bytes: &[u8]) -> io::Result<Vec<(u8, &[u8])>> {
+    let mut tlvs = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 3 {
+            return Err(invalid("truncated PROXY v2 TLV"));
+        }
+        let len = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        if bytes.len() < 3 + len {
+            return Err(invalid("truncated PROXY v2 TLV"));
+        }
+        tlvs.push((bytes[0], &bytes[3..3 + len]));
+        bytes = &bytes[3 + len..];
+    }
+    Ok(tlvs)
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_header, ProxyProtocol, V2_SIGNATURE};
+    use std::io::{Cursor, Read};
+    use std::net::SocketAddr;
+
+    fn peer() -> SocketAddr {
+        "10.0.0.2:40000".parse().unwrap()
+    }
+
+    fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut tlv = vec![kind];
+        tlv.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        tlv.extend_from_slice(value);
+        tlv
+    }
+
+    #[test]
+    fn v1() {
+        let mut input = Cursor::new(&b"PROXY TCP4 198.51.100.7 10.0.0.1 5555 80\r\nGET /"[..]);
+        let proxied = read_header(&mut input, ProxyProtocol::V1, peer()).unwrap();
+        assert_eq!(proxied.source, "198.51.100.7:5555".parse().unwrap());
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET /");
+
+        let mut input = Cursor::new(&b"PROXY UNKNOWN\r\n"[..]);
+        let proxied = read_header(&mut input, ProxyProtocol::V1, peer()).unwrap();
+        assert_eq!(proxied.source, peer());
+
+        let mut input = Cursor::new(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(read_header(&mut input, ProxyProtocol::V1, peer()).is_err());
+    }
+
+    #[test]
+    fn v2_with_tls() {
+        let mut ssl = vec![0x01 | 0x02, 0, 0, 0, 0];
+        ssl.extend(tlv(0x21, b"TLSv1.3"));
+        ssl.extend(tlv(0x22, b"client.example"));
+        let mut body = vec![198, 51, 100, 7, 10, 0, 0, 1, 0x15, 0xb3, 0, 80];
+        body.extend(tlv(0x02, b"api.example"));
+        body.extend(tlv(0x20, &ssl));
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11]);
+        input.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        input.extend(body);
+        input.extend_from_slice(b"GET /");
+
+        let mut input = Cursor::new(input);
+        let proxied = read_header(&mut input, ProxyProtocol::V2, peer()).unwrap();
+        assert_eq!(proxied.source, "198.51.100.7:5555".parse().unwrap());
+        let tls = proxied.tls.unwrap();
+        assert_eq!(tls.version(), Some("TLSv1.3"));
+        assert_eq!(tls.common_name(), Some("client.example"));
+        assert_eq!(tls.sni(), Some("api.example"));
+        assert!(tls.verified());
+        let mut rest = String::new();
+        input.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET /");
+    }
+
+    #[test]
+    fn v2_local() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let proxied = read_header(&mut Cursor::new(input), ProxyProtocol::V2, peer()).unwrap();
+        assert_eq!(proxied.source, peer());
+        assert_eq!(proxied.tls, None);
+    }
+}
@@ -2,8 +2,9 @@ use libc::{c_char, c_int, c_long, c_void, size_t};
 use std::ffi::{CStr, CString};
 use std::io;
 use std::marker;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::panic;
-use std::ptr::{null, null_mut};
+use std::ptr::null;
 use std::str;
 
 use conduit::header::HeaderName;
@@ -27,20 +28,86 @@ extern "C" {
     fn mg_write(connection: *mut MgConnection, data: *const c_void, len: size_t) -> c_int;
     fn mg_get_header(connection: *mut MgConnection, name: *const c_char) -> *const c_char;
     fn mg_get_request_info(connection: *mut MgConnection) -> *mut MgRequestInfo;
+    fn mg_get_valid_options() -> *const MgOption;
+    fn mg_get_ports(
+        context: *const MgContext,
+        size: size_t,
+        ports: *mut c_int,
+        ssl: *mut c_int,
+    ) -> size_t;
+    fn mg_cry(connection: *const MgConnection, fmt: *const c_char, ...);
+}
+
+#[repr(C)]
+struct MgOption {
+    name: *const c_char,
+    kind: c_int,
+    default_value: *const c_char,
 }
 
 pub enum MgContext {}
 
-pub struct Server<T: Sync + 'static>(*mut MgContext, Box<ServerCallback<T>>);
+pub struct Server<T: Sync + 'static>(
+    *mut MgContext,
+    // Kept alive for as long as civetweb may call back into it
+    #[allow(dead_code)] Box<ServerCallback<T>>,
+);
 
 pub struct ServerCallback<T> {
     callback: fn(&mut Connection, &T) -> Result<(), ()>,
+    http_error: Option<fn(&mut Connection, &T, u16) -> bool>,
+    begin_request: Option<fn(&mut Connection, &T)>,
+    end_request: Option<fn(&Connection, &T, u16)>,
+    connection_close: Option<fn(&Connection, &T)>,
+    init_ssl: Option<fn(*mut c_void, &T)>,
     param: T,
 }
 
 impl<T: Sync> ServerCallback<T> {
     pub fn new(callback: fn(&mut Connection, &T) -> Result<(), ()>, param: T) -> ServerCallback<T> {
-        ServerCallback { callback, param }
+        ServerCallback {
+            callback,
+            http_error: None,
+            begin_request: None,
+            end_request: None,
+            connection_close: None,
+            init_ssl: None,
+            param,
+        }
+    }
+
+    /// Called once civetweb has parsed a request, before it is dispatched.
+    pub fn begin_request(mut self, callback: fn(&mut Connection, &T)) -> Self {
+        self.begin_request = Some(callback);
+        self
+    }
+
+    /// Called after a response has been sent, with the status civetweb
+    /// recorded for it.
+    pub fn end_request(mut self, callback: fn(&Connection, &T, u16)) -> Self {
+        self.end_request = Some(callback);
+        self
+    }
+
+    /// Called when a connection is about to be closed.
+    pub fn connection_close(mut self, callback: fn(&Connection, &T)) -> Self {
+        self.connection_close = Some(callback);
+        self
+    }
+
+    /// Called with civetweb's `SSL_CTX` before it loads `ssl_certificate`
+    /// into it.
+    #[cfg(feature = "tls")]
+    pub fn init_ssl(mut self, callback: fn(*mut c_void, &T)) -> Self {
+        self.init_ssl = Some(callback);
+        self
+    }
+
+    /// Called when civetweb sends an error response itself. Returns whether
+    /// a response was written, otherwise civetweb sends its default page.
+    pub fn http_error(mut self, callback: fn(&mut Connection, &T, u16) -> bool) -> Self {
+        self.http_error = Some(callback);
+        self
     }
 }
 
@@ -50,17 +117,69 @@ impl<T: 'static + Sync> Server<T> {
         context
     }
 
+    /// The ports listened on, in the order of `listening_ports`, with any
+    /// port 0 replaced by the one chosen for it.
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports = [0; 32];
+        let mut ssl = [0; 32];
+        let n = unsafe {
+            mg_get_ports(
+                self.as_ptr(),
+                ports.len(),
+                ports.as_mut_ptr(),
+                ssl.as_mut_ptr(),
+            )
+        };
+        ports[..n].iter().map(|&port| port as u16).collect()
+    }
+
+    /// The state passed to the callbacks.
+    #[cfg(feature = "tls")]
+    pub fn param(&self) -> &T {
+        &self.1.param
+    }
+
     pub fn start(options: Config, callback: ServerCallback<T>) -> io::Result<Server<T>> {
-        let (_a, ptrs) = ::config::config_to_options(&options);
+        let (opts, ptrs) = ::config::config_to_options(&options)?;
+        let valid = valid_option_names();
+        for name in opts.iter().step_by(2) {
+            let name = name.to_str().unwrap_or_default();
+            if !valid.contains(&name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown civetweb option `{}`", name),
+                ));
+            }
+        }
 
-        let context = start(ptrs.as_ptr() as *const _);
+        let mut callbacks = MgCallbacks::new();
+        if callback.http_error.is_some() {
+            callbacks.http_error = raw_http_error::<T> as *const c_void;
+        }
+        if callback.begin_request.is_some() {
+            callbacks.begin_request = raw_begin_request::<T> as *const c_void;
+        }
+        if callback.end_request.is_some() {
+            callbacks.end_request = raw_end_request::<T> as *const c_void;
+        }
+        if callback.connection_close.is_some() {
+            callbacks.connection_close = raw_connection_close::<T> as *const c_void;
+        }
+        if callback.init_ssl.is_some() {
+            callbacks.init_ssl = raw_init_ssl::<T> as *const c_void;
+        }
+        let mut callback = Box::new(callback);
+        let context = start(
+            &callbacks,
+            &mut *callback as *mut _ as *mut c_void,
+            ptrs.as_ptr() as *const _,
+        );
         // TODO: fill in this error
         if context.is_null() {
-            return Err(io::Error::new(io::ErrorKind::Other, "other error"));
+            return Err(io::Error::other("other error"));
         }
 
         let uri = CString::new("**").unwrap();
-        let mut callback = Box::new(callback);
         unsafe {
             mg_set_request_handler(
                 context,
@@ -98,6 +217,101 @@ extern "C" fn raw_handler<T: 'static>(conn: *mut MgConnection, param: *mut c_voi
     }
 }
 
+/// Recovers the `ServerCallback` passed to `mg_start` as user data.
+unsafe fn server_callback<'a, T>(conn: *mut MgConnection) -> Option<&'a ServerCallback<T>> {
+    let info = mg_get_request_info(conn);
+    if info.is_null() || (*info).user_data.is_null() {
+        None
+    } else {
+        Some(&*((*info).user_data as *const ServerCallback<T>))
+    }
+}
+
+extern "C" fn raw_http_error<T: 'static>(conn: *mut MgConnection, status: c_int) -> c_int {
+    struct Env(*mut MgConnection);
+    unsafe impl Send for Env {}
+
+    let env = Env(conn);
+    let ret = panic::catch_unwind(move || {
+        let Env(conn) = env;
+        let callback = unsafe { server_callback::<T>(conn) }?;
+        let http_error = callback.http_error?;
+        let mut connection = Connection(conn);
+        Some(http_error(&mut connection, &callback.param, status as u16))
+    });
+
+    match ret {
+        Ok(Some(true)) => 0,
+        _ => 1,
+    }
+}
+
+extern "C" fn raw_begin_request<T: 'static>(conn: *mut MgConnection) -> c_int {
+    struct Env(*mut MgConnection);
+    unsafe impl Send for Env {}
+
+    let env = Env(conn);
+    let _ = panic::catch_unwind(move || {
+        let Env(conn) = env;
+        if let Some(callback) = unsafe { server_callback::<T>(conn) } {
+            if let Some(begin_request) = callback.begin_request {
+                begin_request(&mut Connection(conn), &callback.param);
+            }
+        }
+    });
+
+    // Let civetweb go on to dispatch the request
+    0
+}
+
+extern "C" fn raw_end_request<T: 'static>(conn: *mut MgConnection, status: c_int) {
+    struct Env(*mut MgConnection);
+    unsafe impl Send for Env {}
+
+    let env = Env(conn);
+    let _ = panic::catch_unwind(move || {
+        let Env(conn) = env;
+        if let Some(callback) = unsafe { server_callback::<T>(conn) } {
+            if let Some(end_request) = callback.end_request {
+                let status = if status > 0 { status as u16 } else { 0 };
+                end_request(&Connection(conn), &callback.param, status);
+            }
+        }
+    });
+}
+
+extern "C" fn raw_connection_close<T: 'static>(conn: *mut MgConnection) {
+    struct Env(*mut MgConnection);
+    unsafe impl Send for Env {}
+
+    let env = Env(conn);
+    let _ = panic::catch_unwind(move || {
+        let Env(conn) = env;
+        if let Some(callback) = unsafe { server_callback::<T>(conn) } {
+            if let Some(connection_close) = callback.connection_close {
+                connection_close(&Connection(conn), &callback.param);
+            }
+        }
+    });
+}
+
+extern "C" fn raw_init_ssl<T: 'static>(ssl_ctx: *mut c_void, user_data: *mut c_void) -> c_int {
+    struct Env(*mut c_void, *mut c_void);
+    unsafe impl Send for Env {}
+
+    let env = Env(ssl_ctx, user_data);
+    let _ = panic::catch_unwind(move || {
+        let Env(ssl_ctx, user_data) = env;
+        let callback: &ServerCallback<T> = unsafe { &*(user_data as *const ServerCallback<T>) };
+        if let Some(init_ssl) = callback.init_ssl {
+            init_ssl(ssl_ctx, &callback.param);
+        }
+    });
+
+    // Let civetweb go on to load ssl_certificate
+    0
+}
+
 pub enum MgConnection {}
 
 pub struct Connection(*mut MgConnection);
@@ -173,6 +387,16 @@ impl<'a> RequestInfo<'a> {
         to_byte_slice(self.as_ref(), |info| info.request_method)
     }
 
+    /// Whether the URL and query string can be read as UTF-8
+    pub fn is_utf8(&self) -> bool {
+        [
+            to_byte_slice(self.as_ref(), |info| info.uri),
+            to_byte_slice(self.as_ref(), |info| info.query_string),
+        ]
+        .iter()
+        .all(|bytes| bytes.is_none_or(|b| str::from_utf8(b).is_ok()))
+    }
+
     pub fn url(&self) -> Option<&str> {
         to_str_slice(self.as_ref(), |info| info.uri)
     }
@@ -185,6 +409,26 @@ impl<'a> RequestInfo<'a> {
         to_str_slice(self.as_ref(), |info| info.query_string)
     }
 
+    /// The user civetweb authenticated the request as, if any.
+    pub fn remote_user(&self) -> Option<&str> {
+        to_str_slice(self.as_ref(), |info| info.remote_user)
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        let ip = Ipv4Addr::from(self.remote_ip() as u32);
+        SocketAddr::V4(SocketAddrV4::new(ip, self.remote_port()))
+    }
+
+    /// The pointer civetweb keeps for the connection across keep-alive
+    /// requests.
+    pub fn conn_data(&self) -> *mut c_void {
+        self.as_ref().conn_data
+    }
+
+    pub fn set_conn_data(&self, data: *mut c_void) {
+        unsafe { (*self.as_ptr()).conn_data = data }
+    }
+
     pub fn remote_ip(&self) -> i32 {
         self.as_ref().remote_ip as i32
     }
@@ -253,8 +497,27 @@ where
     to_byte_slice(obj, callback).map(|bytes| str::from_utf8(bytes).unwrap())
 }
 
-pub fn start(options: *const *mut c_char) -> *mut MgContext {
-    unsafe { mg_start(&MgCallbacks::new(), null_mut(), options) }
+/// The names of all options understood by civetweb.
+pub fn valid_option_names() -> Vec<&'static str> {
+    let mut names = Vec::new();
+    unsafe {
+        let mut option = mg_get_valid_options();
+        while !option.is_null() && !(*option).name.is_null() {
+            if let Ok(name) = CStr::from_ptr((*option).name).to_str() {
+                names.push(name);
+            }
+            option = option.offset(1);
+        }
+    }
+    names
+}
+
+fn start(
+    callbacks: &MgCallbacks,
+    user_data: *mut c_void,
+    options: *const *mut c_char,
+) -> *mut MgContext {
+    unsafe { mg_start(callbacks, user_data, options) }
 }
 
 pub fn read(conn: &Connection, buf: &mut [u8]) -> i32 {
@@ -267,6 +530,13 @@ pub fn read(conn: &Connection, buf: &mut [u8]) -> i32 {
     }
 }
 
+/// Writes `message` to civetweb's error log.
+pub fn cry(conn: &Connection, message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    let fmt = CString::new("%s").unwrap();
+    unsafe { mg_cry(conn.unwrap(), fmt.as_ptr(), message.as_ptr()) }
+}
+
 pub fn write(conn: &Connection, bytes: &[u8]) -> i32 {
     let c_bytes = bytes.as_ptr() as *const c_void;
     unsafe { mg_write(conn.unwrap(), c_bytes, bytes.len() as size_t) }
@@ -278,6 +548,41 @@ pub fn get_header(conn: &Connection, string: HeaderName) -> Option<&str> {
     unsafe { to_str_slice(conn, |conn| mg_get_header(conn.unwrap(), string.as_ptr())) }
 }
 
+/// Makes civetweb close the connection after the current request, as if the
+/// client had sent `Connection: close`.
+pub fn close_after_request(conn: &Connection) {
+    static NAME: &[u8] = b"Connection\0";
+    static VALUE: &[u8] = b"close\0";
+
+    let info = match get_request_info(conn) {
+        Some(info) => info,
+        None => return,
+    };
+    unsafe {
+        let info = &mut *info.as_ptr();
+        let len = info.num_headers.clamp(0, 64) as usize;
+        let existing = info.headers[..len].iter().position(|header| {
+            !header.name.is_null()
+                && CStr::from_ptr(header.name)
+                    .to_bytes()
+                    .eq_ignore_ascii_case(b"connection")
+        });
+        let i = match existing {
+            Some(i) => i,
+            None if len < 64 => {
+                info.num_headers = len as c_int + 1;
+                len
+            }
+            // civetweb only looks at the first `Connection` header
+            None => 63,
+        };
+        info.headers[i] = MgHeader {
+            name: NAME.as_ptr() as *const c_char,
+            value: VALUE.as_ptr() as *const c_char,
+        };
+    }
+}
+
 pub fn get_request_info(conn: &Connection) -> Option<RequestInfo<'_>> {
     unsafe {
         let info = mg_get_request_info(conn.unwrap());
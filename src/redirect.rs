@@ -0,0 +1,244 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client may take to send its request line and headers.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest request head read before the client is sent a 400.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// The most clients a listener redirects at once. Further connections are
+/// closed as they are accepted.
+const MAX_CONNECTIONS: usize = 256;
+
+/// The status a plain HTTP listener redirects to HTTPS with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpsRedirect {
+    /// 301, which clients may follow with a `GET`.
+    MovedPermanently,
+    /// 308, which clients must follow with the same method and body.
+    PermanentRedirect,
+}
+
+impl HttpsRedirect {
+    fn status_line(self) -> &'static str {
+        match self {
+            HttpsRedirect::MovedPermanently => "301 Moved Permanently",
+            HttpsRedirect::PermanentRedirect => "308 Permanent Redirect",
+        }
+    }
+}
+
+/// A plain HTTP listener that redirects every request to HTTPS.
+///
+/// civetweb 1.6 has `r` listeners for this, but they always answer with a
+/// 302, which lets clients change the method, and drop the query string.
+/// These are served by a thread of their own instead.
+pub(crate) struct Redirector {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Redirector {
+    pub(crate) fn start(
+        port: u16,
+        https_port: u16,
+        status: HttpsRedirect,
+    ) -> io::Result<Redirector> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let active = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn(move || {
+            for client in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => continue,
+                };
+                if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let finished = active.clone();
+                let spawned = thread::Builder::new().spawn(move || {
+                    redirect(client, https_port, status);
+                    finished.fetch_sub(1, Ordering::SeqCst);
+                });
+                if spawned.is_err() {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        });
+        Ok(Redirector {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Redirector {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so that it sees the flag
+        let wake = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.addr.port());
+        let _ = TcpStream::connect(wake);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn redirect(mut client: TcpStream, https_port: u16, status: HttpsRedirect) {
+    let _ = client.set_read_timeout(Some(HEAD_TIMEOUT));
+    let location = read_head(&mut client)
+        .ok()
+        .and_then(|head| location(&head, https_port));
+    let response = match location {
+        Some(location) => format!(
+            "HTTP/1.1 {}\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status.status_line(),
+            location
+        ),
+        None => {
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    let _ = client.write_all(response.as_bytes());
+}
+
+/// Reads up to the blank line that ends the request head.
+fn read_head(client: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if head.len() > MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "head too long"));
+        }
+        let n = client.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(head).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not UTF-8"))
+}
+
+/// The HTTPS URL for a request head, keeping its path and query.
+fn location(head: &str, https_port: u16) -> Option<String> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let _method = request_line.next()?;
+    let mut target = request_line.next()?;
+    // An absolute-form target carries the host itself
+    let mut host = None;
+    for scheme in &["http://", "https://"] {
+        if target.len() > scheme.len() && target[..scheme.len()].eq_ignore_ascii_case(scheme) {
+            let rest = &target[scheme.len()..];
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            host = Some(&rest[..end]);
+            target = &rest[end..];
+        }
+    }
+    if host.is_none() {
+        host = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next()?.trim();
+                let value = parts.next()?.trim();
+                if name.eq_ignore_ascii_case("host") {
+                    Some(value)
+                } else {
+                    None
+                }
+            })
+            .next();
+    }
+    let host = strip_port(host?);
+    let valid_host = !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-.[]:".contains(&b));
+    if !valid_host {
+        return None;
+    }
+    let slash = if target.starts_with('/') { "" } else { "/" };
+    if !(slash.is_empty() || target.is_empty() || target.starts_with('?'))
+        || target.bytes().any(|b| b.is_ascii_control())
+    {
+        return None;
+    }
+    if https_port == 443 {
+        Some(format!("https://{}{}{}", host, slash, target))
+    } else {
+        Some(format!(
+            "https://{}:{}{}{}",
+            host, https_port, slash, target
+        ))
+    }
+}
+
+/// Drops the port from a `Host`, leaving IPv6 literals in brackets.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{location, HttpsRedirect, Redirector};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use test::port;
+
+    #[test]
+    fn builds_location() {
+        let head = "GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n";
+        assert_eq!(
+            location(head, 8443).as_deref(),
+            Some("https://example.com:8443/a/b?x=1&y=2")
+        );
+        let head = "GET / HTTP/1.1\r\nhost: [::1]:80\r\n\r\n";
+        assert_eq!(location(head, 443).as_deref(), Some("https://[::1]/"));
+        let head = "GET http://example.com?q HTTP/1.1\r\nHost: other\r\n\r\n";
+        assert_eq!(
+            location(head, 443).as_deref(),
+            Some("https://example.com/?q")
+        );
+        assert_eq!(location("GET / HTTP/1.0\r\n\r\n", 443), None);
+        assert_eq!(location("GET / HTTP/1.1\r\nHost: a/b\r\n\r\n", 443), None);
+    }
+
+    #[test]
+    fn redirects_requests() {
+        let port = port();
+        let _redirector = Redirector::start(port, 8443, HttpsRedirect::PermanentRedirect).unwrap();
+        let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        s.write_all(b"POST /upload?id=7 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nLocation: https://localhost:8443/upload?id=7\r\n"),
+            "{}",
+            response
+        );
+    }
+}
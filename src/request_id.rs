@@ -0,0 +1,78 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use conduit::header::HeaderName;
+use conduit::HeaderMap;
+
+/// Longest incoming request id that is accepted rather than replaced.
+const MAX_LEN: usize = 200;
+
+/// The id of a request, in its extensions alongside `StartInstant`.
+///
+/// Taken from the request's `X-Request-Id` header, or the header set with
+/// `Config::request_id_header`, when it holds up to 200 visible ASCII
+/// characters, and generated otherwise. The same header carries it back in
+/// the response, and hooks and panic reports see it too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Reuses the id in `header`, or generates a new one.
+    pub(crate) fn from_headers(headers: &HeaderMap, header: &HeaderName) -> RequestId {
+        let incoming = headers
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+            .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()));
+        match incoming {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(random_hex(16)),
+        }
+    }
+}
+
+/// Random lowercase hex for ids that only need to be unique, not
+/// unpredictable. Never all zeros.
+pub(crate) fn random_hex(bytes: usize) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut out = String::with_capacity(bytes * 2);
+    while out.len() < bytes * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        let _ = write!(out, "{:016x}", hasher.finish() | 1);
+    }
+    out.truncate(bytes * 2);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::RequestId;
+    use conduit::header::HeaderName;
+    use conduit::HeaderMap;
+
+    #[test]
+    fn reuses_or_generates() {
+        let request_id = HeaderName::from_static("x-request-id");
+        let correlation_id = HeaderName::from_static("x-correlation-id");
+        let mut headers = HeaderMap::new();
+        let generated = RequestId::from_headers(&headers, &request_id);
+        assert_eq!(generated.0.len(), 32);
+        assert_ne!(generated, RequestId::from_headers(&headers, &request_id));
+
+        headers.insert(&request_id, "abc-123".parse().unwrap());
+        headers.insert(&correlation_id, "has space".parse().unwrap());
+        let id = RequestId::from_headers(&headers, &request_id);
+        assert_eq!(id.0, "abc-123");
+        let id = RequestId::from_headers(&headers, &correlation_id);
+        assert_eq!(id.0.len(), 32);
+    }
+}
@@ -0,0 +1,213 @@
+use std::io;
+
+use conduit::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+/// Security headers added to every response that does not set them itself.
+///
+/// Register with `Config::security_headers`. Unlike a `Layer`, the policy
+/// also covers responses from the `ErrorHandler`, 400 responses to
+/// malformed requests and errors generated by civetweb, except pages
+/// rendered from `Config::error_pages` templates.
+///
+/// The defaults are `Strict-Transport-Security: max-age=31536000`, sent only
+/// on HTTPS requests, `X-Content-Type-Options: nosniff`,
+/// `X-Frame-Options: DENY` and
+/// `Referrer-Policy: strict-origin-when-cross-origin`. No
+/// `Content-Security-Policy` is sent unless one is set. Pass `None` to a
+/// setter to stop sending that header.
+///
+/// ```
+/// # extern crate civet;
+/// use civet::{Config, SecurityHeaders};
+///
+/// let mut headers = SecurityHeaders::new();
+/// headers
+///     .content_security_policy(Some("default-src 'self'"))
+///     .frame_options(Some("SAMEORIGIN"))
+///     .remove_server_header();
+///
+/// let mut config = Config::new();
+/// config.security_headers(headers);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    hsts: Option<String>,
+    content_type_options: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    content_security_policy: Option<String>,
+    server: ServerHeader,
+}
+
+#[derive(Clone, Debug)]
+enum ServerHeader {
+    Keep,
+    Remove,
+    Set(String),
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts: Some("max-age=31536000".to_string()),
+            content_type_options: Some("nosniff".to_string()),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            content_security_policy: None,
+            server: ServerHeader::Keep,
+        }
+    }
+
+    /// The `Strict-Transport-Security` value for HTTPS responses.
+    pub fn strict_transport_security(&mut self, value: Option<&str>) -> &mut SecurityHeaders {
+        self.hsts = value.map(str::to_string);
+        self
+    }
+
+    pub fn content_type_options(&mut self, value: Option<&str>) -> &mut SecurityHeaders {
+        self.content_type_options = value.map(str::to_string);
+        self
+    }
+
+    pub fn frame_options(&mut self, value: Option<&str>) -> &mut SecurityHeaders {
+        self.frame_options = value.map(str::to_string);
+        self
+    }
+
+    pub fn referrer_policy(&mut self, value: Option<&str>) -> &mut SecurityHeaders {
+        self.referrer_policy = value.map(str::to_string);
+        self
+    }
+
+    pub fn content_security_policy(&mut self, value: Option<&str>) -> &mut SecurityHeaders {
+        self.content_security_policy = value.map(str::to_string);
+        self
+    }
+
+    /// Replaces any `Server` header the handler sets with `value`.
+    pub fn server_header(&mut self, value: &str) -> &mut SecurityHeaders {
+        self.server = ServerHeader::Set(value.to_string());
+        self
+    }
+
+    /// Strips any `Server` header the handler sets.
+    pub fn remove_server_header(&mut self) -> &mut SecurityHeaders {
+        self.server = ServerHeader::Remove;
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+/// `SecurityHeaders` with their values checked, ready to apply.
+pub(crate) struct Policy {
+    hsts: Option<HeaderValue>,
+    defaults: Vec<(HeaderName, HeaderValue)>,
+    server: Option<Option<HeaderValue>>,
+}
+
+impl Policy {
+    /// Fails if any of the values cannot be sent as a header.
+    pub(crate) fn new(headers: &SecurityHeaders) -> io::Result<Policy> {
+        fn value(value: &str) -> io::Result<HeaderValue> {
+            HeaderValue::from_str(value).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid security header value `{}`", value),
+                )
+            })
+        }
+
+        let mut defaults = Vec::new();
+        let configured = [
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                &headers.content_type_options,
+            ),
+            (header::X_FRAME_OPTIONS, &headers.frame_options),
+            (header::REFERRER_POLICY, &headers.referrer_policy),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &headers.content_security_policy,
+            ),
+        ];
+        for (name, configured) in configured {
+            if let Some(configured) = configured {
+                defaults.push((name, value(configured)?));
+            }
+        }
+        Ok(Policy {
+            hsts: headers.hsts.as_deref().map(value).transpose()?,
+            defaults,
+            server: match headers.server {
+                ServerHeader::Keep => None,
+                ServerHeader::Remove => Some(None),
+                ServerHeader::Set(ref server) => Some(Some(value(server)?)),
+            },
+        })
+    }
+
+    /// Adds the headers missing from a response to a request made over
+    /// HTTPS if `https` is set.
+    pub(crate) fn apply(&self, https: bool, headers: &mut HeaderMap) {
+        if https {
+            if let Some(hsts) = &self.hsts {
+                headers
+                    .entry(header::STRICT_TRANSPORT_SECURITY)
+                    .or_insert_with(|| hsts.clone());
+            }
+        }
+        for (name, value) in &self.defaults {
+            headers.entry(name).or_insert_with(|| value.clone());
+        }
+        match &self.server {
+            None => {}
+            Some(None) => {
+                headers.remove(header::SERVER);
+            }
+            Some(Some(server)) => {
+                headers.insert(header::SERVER, server.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, SecurityHeaders};
+    use conduit::header::{self, HeaderMap};
+
+    #[test]
+    fn fills_in_missing_headers() {
+        let mut settings = SecurityHeaders::new();
+        settings.referrer_policy(None).server_header("civet");
+        let policy = Policy::new(&settings).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::X_FRAME_OPTIONS, "SAMEORIGIN".parse().unwrap());
+        headers.insert(header::SERVER, "handler/1.0".parse().unwrap());
+        policy.apply(false, &mut headers);
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(headers[header::SERVER], "civet");
+        assert!(!headers.contains_key(header::REFERRER_POLICY));
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        policy.apply(true, &mut headers);
+        assert_eq!(
+            headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut settings = SecurityHeaders::new();
+        settings.content_security_policy(Some("default-src\n'self'"));
+        assert!(Policy::new(&settings).is_err());
+    }
+}
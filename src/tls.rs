@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use libc::c_void;
+use openssl::ssl::{NameType, SniError, SslContext, SslContextBuilder, SslFiletype, SslMethod};
+
+/// Certificates picked by the hostname a client asks for with SNI.
+///
+/// Register with `Config::sni_certificates`. Each file is a PEM holding a
+/// private key and certificate chain, like `Config::ssl_certificate`, which
+/// is still required and serves clients that ask for no hostname or one
+/// without a certificate here. A hostname of `*.example.com` matches any
+/// single label in place of the `*`; exact names take precedence.
+///
+/// All the files are read again by `Server::reload_tls`.
+///
+/// ```
+/// # extern crate civet;
+/// use civet::{Config, SniCertificates};
+///
+/// let mut certificates = SniCertificates::new();
+/// certificates
+///     .add("example.com", "certs/example.com.pem")
+///     .add("*.example.org", "certs/example.org.pem");
+///
+/// let mut config = Config::new();
+/// config
+///     .https_port(443)
+///     .ssl_certificate("certs/default.pem")
+///     .sni_certificates(certificates);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SniCertificates {
+    hosts: Vec<(String, PathBuf)>,
+}
+
+impl SniCertificates {
+    pub fn new() -> SniCertificates {
+        SniCertificates::default()
+    }
+
+    /// Serves `pem` to clients asking for `hostname`.
+    pub fn add<P: AsRef<Path>>(&mut self, hostname: &str, pem: P) -> &mut SniCertificates {
+        self.hosts
+            .push((hostname.to_ascii_lowercase(), pem.as_ref().to_path_buf()));
+        self
+    }
+}
+
+/// The loaded certificates, swapped as a whole on reload.
+struct Contexts {
+    default: SslContext,
+    hosts: HashMap<String, SslContext>,
+}
+
+/// Chooses a certificate for each TLS handshake from files that can be
+/// reloaded while the server runs.
+pub(crate) struct Tls {
+    default: PathBuf,
+    hosts: Vec<(String, PathBuf)>,
+    contexts: RwLock<Contexts>,
+}
+
+impl Tls {
+    /// Fails if any of the files cannot be loaded.
+    pub(crate) fn new(default: &Path, sni: &SniCertificates) -> io::Result<Tls> {
+        let default = default.to_path_buf();
+        let hosts = sni.hosts.clone();
+        let contexts = RwLock::new(load(&default, &hosts)?);
+        Ok(Tls {
+            default,
+            hosts,
+            contexts,
+        })
+    }
+
+    /// Reads every file again. On failure the current certificates stay in
+    /// use.
+    pub(crate) fn reload(&self) -> io::Result<()> {
+        let contexts = load(&self.default, &self.hosts)?;
+        *self.contexts.write().unwrap_or_else(|e| e.into_inner()) = contexts;
+        Ok(())
+    }
+
+    fn select(&self, hostname: Option<&str>) -> SslContext {
+        let contexts = self.contexts.read().unwrap_or_else(|e| e.into_inner());
+        hostname
+            .map(str::to_ascii_lowercase)
+            .and_then(|hostname| {
+                let wildcard = hostname
+                    .find('.')
+                    .map(|dot| format!("*{}", &hostname[dot..]));
+                contexts
+                    .hosts
+                    .get(&hostname)
+                    .or_else(|| contexts.hosts.get(&wildcard?))
+            })
+            .unwrap_or(&contexts.default)
+            .clone()
+    }
+
+    /// Makes the `SSL_CTX` civetweb accepts connections with switch to the
+    /// chosen certificate during each handshake.
+    pub(crate) fn install(tls: &Arc<Tls>, ssl_ctx: *mut c_void) {
+        // civet-sys links civetweb to the same OpenSSL as the `openssl` crate
+        // under the `tls` feature, so this is the `SSL_CTX` type it expects.
+        // civetweb owns the context, so it must not be freed here.
+        let mut builder =
+            ManuallyDrop::new(unsafe { SslContextBuilder::from_ptr(ssl_ctx as *mut _) });
+        let tls = tls.clone();
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = tls.select(ssl.servername(NameType::HOST_NAME));
+            ssl.set_ssl_context(&context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+    }
+}
+
+fn load(default: &Path, hosts: &[(String, PathBuf)]) -> io::Result<Contexts> {
+    Ok(Contexts {
+        default: context(default)?,
+        hosts: hosts
+            .iter()
+            .map(|(hostname, pem)| Ok((hostname.clone(), context(pem)?)))
+            .collect::<io::Result<_>>()?,
+    })
+}
+
+fn context(pem: &Path) -> io::Result<SslContext> {
+    let invalid = |e: openssl::error::ErrorStack| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot load certificate `{}`: {}", pem.display(), e),
+        )
+    };
+    let mut builder = SslContextBuilder::new(SslMethod::tls_server()).map_err(invalid)?;
+    builder
+        .set_private_key_file(pem, SslFiletype::PEM)
+        .map_err(invalid)?;
+    builder.set_certificate_chain_file(pem).map_err(invalid)?;
+    builder.check_private_key().map_err(invalid)?;
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use libc::c_void;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Name, X509Ref, X509};
+
+    use super::{SniCertificates, Tls};
+
+    static SERIAL: AtomicU32 = AtomicU32::new(1);
+
+    fn name(cn: &str) -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        name.build()
+    }
+
+    pub(crate) fn certificate(
+        cn: &str,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(SERIAL.fetch_add(1, Ordering::Relaxed)).unwrap();
+        cert.set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&name(cn)).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((ca, ca_key)) => {
+                cert.set_issuer_name(ca.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .dns(cn)
+                    .build(&cert.x509v3_context(Some(ca), None))
+                    .unwrap();
+                cert.append_extension(san).unwrap();
+                cert.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                cert.set_issuer_name(&name(cn)).unwrap();
+                cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (cert.build(), key)
+    }
+
+    pub(crate) fn write_leaf(path: &Path, cn: &str, ca: &(X509, PKey<Private>)) {
+        let (cert, key) = certificate(cn, Some((&ca.0, &ca.1)));
+        let mut pem = key.private_key_to_pem_pkcs8().unwrap();
+        pem.extend(cert.to_pem().unwrap());
+        fs::write(path, pem).unwrap();
+    }
+
+    pub(crate) fn common_name(cert: &X509Ref) -> String {
+        let name = cert.subject_name();
+        let entry = name.entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        String::from_utf8(entry.data().as_slice().to_vec()).unwrap()
+    }
+
+    fn selected(tls: &Tls, hostname: Option<&str>) -> String {
+        common_name(tls.select(hostname).certificate().unwrap())
+    }
+
+    #[test]
+    fn selects_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = certificate("Test CA", None);
+        let default = dir.path().join("default.pem");
+        let api = dir.path().join("api.pem");
+        let wildcard = dir.path().join("wildcard.pem");
+        write_leaf(&default, "localhost", &ca);
+        write_leaf(&api, "api.example.com", &ca);
+        write_leaf(&wildcard, "*.example.com", &ca);
+
+        let mut sni = SniCertificates::new();
+        sni.add("API.example.com", &api)
+            .add("*.example.com", &wildcard);
+        let tls = Tls::new(&default, &sni).unwrap();
+        assert_eq!(selected(&tls, Some("api.example.com")), "api.example.com");
+        assert_eq!(selected(&tls, Some("www.example.com")), "*.example.com");
+        assert_eq!(selected(&tls, Some("a.b.example.com")), "localhost");
+        assert_eq!(selected(&tls, None), "localhost");
+
+        write_leaf(&api, "api.example.com.rotated", &ca);
+        tls.reload().unwrap();
+        let rotated = "api.example.com.rotated";
+        assert_eq!(selected(&tls, Some("api.example.com")), rotated);
+
+        fs::write(&api, "not a certificate").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(selected(&tls, Some("api.example.com")), rotated);
+    }
+
+    #[test]
+    fn switches_certificate_during_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = certificate("Test CA", None);
+        let default = dir.path().join("default.pem");
+        let api = dir.path().join("api.pem");
+        write_leaf(&default, "localhost", &ca);
+        write_leaf(&api, "api.example.com", &ca);
+        let mut sni = SniCertificates::new();
+        sni.add("api.example.com", &api);
+        let tls = Arc::new(Tls::new(&default, &sni).unwrap());
+
+        // Stands in for civetweb's SSL_CTX
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key_file(&default, ::openssl::ssl::SslFiletype::PEM)
+            .unwrap();
+        acceptor.set_certificate_chain_file(&default).unwrap();
+        Tls::install(&tls, acceptor.as_ptr() as *mut c_void);
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.cert_store_mut().add_cert(ca.0.clone()).unwrap();
+        let connector = connector.build();
+        for hostname in &["api.example.com", "localhost"] {
+            let stream = TcpStream::connect(addr).unwrap();
+            let stream = connector.connect(hostname, stream).unwrap();
+            let cert = stream.ssl().peer_certificate().unwrap();
+            assert_eq!(common_name(&cert), *hostname);
+        }
+        server.join().unwrap();
+    }
+}
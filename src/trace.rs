@@ -0,0 +1,210 @@
+use conduit::{HeaderMap, Method};
+use tracing::field::Empty;
+use tracing::Span;
+
+use request_id::{random_hex, RequestId};
+
+/// The W3C trace context of a request, inserted into its extensions when
+/// the `tracing` feature is enabled.
+///
+/// The trace is continued from the request's `traceparent` header if it has
+/// a valid one, and started afresh otherwise. `span_id` identifies this
+/// server's span, so `traceparent` is the value to send downstream.
+///
+/// ```
+/// # extern crate civet;
+/// # extern crate conduit;
+/// use civet::TraceContext;
+/// use conduit::RequestExt;
+///
+/// fn downstream_headers(req: &dyn RequestExt) -> Vec<(&'static str, String)> {
+///     let mut headers = Vec::new();
+///     if let Some(trace) = req.extensions().find::<TraceContext>() {
+///         headers.push(("traceparent", trace.traceparent()));
+///         if let Some(state) = trace.tracestate() {
+///             headers.push(("tracestate", state.to_string()));
+///         }
+///     }
+///     headers
+/// }
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    trace_id: String,
+    parent_id: Option<String>,
+    span_id: String,
+    flags: u8,
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Continues the trace in `headers`, or starts a new, sampled one.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> TraceContext {
+        let parent = headers
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        match parent {
+            Some((trace_id, parent_id, flags)) => {
+                let tracestate = headers
+                    .get_all("tracestate")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                TraceContext {
+                    trace_id,
+                    parent_id: Some(parent_id),
+                    span_id: random_hex(8),
+                    flags,
+                    tracestate: if tracestate.is_empty() {
+                        None
+                    } else {
+                        Some(tracestate)
+                    },
+                }
+            }
+            None => TraceContext {
+                trace_id: random_hex(16),
+                parent_id: None,
+                span_id: random_hex(8),
+                flags: 1,
+                tracestate: None,
+            },
+        }
+    }
+
+    /// The 32 hex digit trace id.
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    /// The span id from the incoming `traceparent`, if there was one.
+    pub fn parent_id(&self) -> Option<&str> {
+        self.parent_id.as_deref()
+    }
+
+    /// The 16 hex digit id of this request's span.
+    pub fn span_id(&self) -> &str {
+        &self.span_id
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    /// The incoming `tracestate`, passed on unchanged.
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    /// A `traceparent` header value naming this request's span as parent.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// Parses a `traceparent` into its trace id, parent id and flags.
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // Later versions may append fields, but version 00 has exactly four
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let is_zero = |id: &str| id.bytes().all(|b| b == b'0');
+    if !is_hex(trace_id, 32) || is_zero(trace_id) || !is_hex(parent_id, 16) || is_zero(parent_id) {
+        return None;
+    }
+    if !is_hex(flags, 2) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), parent_id.to_string(), flags))
+}
+
+/// Lowercase hex of exactly `len` digits, as W3C trace context requires.
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Opens the span for a request. The status and byte counts are recorded
+/// once the response has been written.
+pub(crate) fn request_span(
+    method: &Method,
+    path: &str,
+    remote_addr: &std::net::SocketAddr,
+    request_id: &RequestId,
+    context: &TraceContext,
+) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %request_id.0,
+        http.method = %method,
+        http.route = path,
+        http.status_code = Empty,
+        net.peer.addr = %remote_addr,
+        bytes_read = Empty,
+        bytes_written = Empty,
+        trace_id = context.trace_id(),
+        span_id = context.span_id(),
+        parent_id = context.parent_id(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_traceparent, TraceContext};
+    use conduit::HeaderMap;
+
+    #[test]
+    fn parses_traceparent() {
+        let (trace_id, parent_id, flags) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent_id, "00f067aa0ba902b7");
+        assert_eq!(flags, 1);
+
+        for invalid in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(parse_traceparent(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn continues_or_starts_traces() {
+        let mut headers = HeaderMap::new();
+        let fresh = TraceContext::from_headers(&headers);
+        assert_eq!(fresh.trace_id().len(), 32);
+        assert_eq!(fresh.parent_id(), None);
+        assert!(fresh.sampled());
+
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("tracestate", "congo=t61rcWkgMzE".parse().unwrap());
+        let trace = TraceContext::from_headers(&headers);
+        assert_eq!(trace.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_id(), Some("00f067aa0ba902b7"));
+        assert_ne!(trace.span_id(), "00f067aa0ba902b7");
+        assert!(!trace.sampled());
+        assert_eq!(trace.tracestate(), Some("congo=t61rcWkgMzE"));
+        assert_eq!(
+            trace.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-00", trace.span_id())
+        );
+    }
+}